@@ -0,0 +1,372 @@
+// whole folders, encrypted as one tar archive, optionally zstd compressed. the archive is built,
+// compressed and encrypted as a single stream, so no plaintext ever touches the disk.
+// extracting checks every entry before it's written: paths have to stay inside the destination,
+// links can't point out of it, and nothing that's already there is replaced. if anything fails part
+// way through, e.g. the last chunk doesn't authenticate, whatever was extracted is removed again.
+use super::{open_armored, with_identities, WildcardIdentity, WildcardRecipient};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader, BufWriter, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+
+/// the first bytes of every zstd frame
+const ZSTD_MAGIC: [u8; 4] = [0x28, 0xb5, 0x2f, 0xfd];
+const TAR_BLOCK: u64 = 512;
+
+/// counts the bytes passing through, for progress reports
+struct Counted<T> {
+    inner: T,
+    count: Arc<AtomicU64>,
+}
+
+impl<W: Write> Write for Counted<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.count.fetch_add(n as u64, Ordering::SeqCst);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<R: Read> Read for Counted<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.count.fetch_add(n as u64, Ordering::SeqCst);
+        Ok(n)
+    }
+}
+
+/// what the archive is named after in the output template. a single folder lends its own name,
+/// several are called `archive`, next to the first one
+pub fn archive_input(directories: &[PathBuf]) -> PathBuf {
+    match directories {
+        [directory] => directory.clone(),
+        _ => directories
+            .first()
+            .map(|directory| directory.with_file_name("archive"))
+            .unwrap_or_default(),
+    }
+}
+
+/// about how big the tar stream of `path` will be: a header block per entry and file contents
+/// padded to whole blocks. only used for progress
+pub fn tar_size(path: &Path) -> io::Result<u64> {
+    let metadata = fs::symlink_metadata(path)?;
+    let mut size = TAR_BLOCK;
+    if metadata.is_dir() {
+        for entry in fs::read_dir(path)? {
+            size += tar_size(&entry?.path())?;
+        }
+    } else if metadata.is_file() {
+        size += metadata.len().div_ceil(TAR_BLOCK) * TAR_BLOCK;
+    }
+    Ok(size)
+}
+
+fn write_tar<W: Write>(
+    output: W,
+    directories: &[PathBuf],
+    count: Arc<AtomicU64>,
+) -> Result<W, String> {
+    let mut builder = tar::Builder::new(Counted {
+        inner: output,
+        count,
+    });
+    // links are archived as links. following them could pull in files from anywhere
+    builder.follow_symlinks(false);
+    for directory in directories {
+        let name = directory
+            .file_name()
+            .ok_or(format!("{} can't be archived", directory.display()))?;
+        builder
+            .append_dir_all(name, directory)
+            .map_err(|e| format!("couldn't archive {}: {e}", directory.display()))?;
+    }
+    let counted = builder.into_inner().map_err(|e| e.to_string())?;
+    Ok(counted.inner)
+}
+
+/// pack `directories` into one tar archive, each under its own name, and encrypt it into `output`.
+/// `count` goes up with every byte of the (uncompressed) archive written
+pub fn encrypt_directories(
+    recipients: &[WildcardRecipient],
+    directories: &[PathBuf],
+    output: File,
+    armor: bool,
+    compress: bool,
+    count: Arc<AtomicU64>,
+) -> Result<(), String> {
+    let format = if armor {
+        age::armor::Format::AsciiArmor
+    } else {
+        age::armor::Format::Binary
+    };
+    let armored = age::armor::ArmoredWriter::wrap_output(BufWriter::new(output), format)
+        .map_err(|e| e.to_string())?;
+    let encryptor =
+        age::Encryptor::with_recipients(recipients.iter().map(|recipient| recipient as _))
+            .map_err(|e| e.to_string())?;
+    let mut encrypted = encryptor.wrap_output(armored).map_err(|e| e.to_string())?;
+
+    if compress {
+        let compressed = zstd::Encoder::new(&mut encrypted, 0).map_err(|e| e.to_string())?;
+        write_tar(compressed, directories, count)?
+            .finish()
+            .map_err(|e| e.to_string())?;
+    } else {
+        write_tar(&mut encrypted, directories, count)?;
+    }
+    encrypted
+        .finish()
+        .and_then(|armored| armored.finish())
+        .and_then(|mut file| file.flush())
+        .map_err(|e| e.to_string())
+}
+
+/// whether `path` only goes down from where it starts: no root, drive or `..`
+fn stays_inside(path: &Path) -> bool {
+    path.components()
+        .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+/// whether a link at `entry` pointing to `target` stays inside `destination`, which has to be
+/// canonical. this is worked out on disk rather than from the names alone, since links extracted
+/// earlier can send `..` somewhere else entirely: with `a/up -> ..`, `a/up2 -> up/..` looks like it
+/// points to `a` but really points out of `destination`. anything that doesn't exist yet could
+/// still be extracted as a link later on, so a `..` after it is refused
+fn link_stays_inside(destination: &Path, entry: &Path, target: &Path) -> bool {
+    let parent = entry.parent().unwrap_or(Path::new(""));
+    let mut resolved = destination.to_path_buf();
+    let mut missing = false;
+    for component in parent.components().chain(target.components()) {
+        match component {
+            Component::Normal(name) => {
+                resolved.push(name);
+                if !missing {
+                    match fs::symlink_metadata(&resolved) {
+                        Ok(metadata) if metadata.is_symlink() => match resolved.canonicalize() {
+                            Ok(real) => resolved = real,
+                            Err(_) => return false,
+                        },
+                        Ok(_) => {}
+                        Err(_) => missing = true,
+                    }
+                }
+            }
+            Component::CurDir => {}
+            Component::ParentDir if !missing => {
+                resolved.pop();
+            }
+            _ => return false,
+        }
+        if !resolved.starts_with(destination) {
+            return false;
+        }
+    }
+    true
+}
+
+fn unpack(
+    archive: impl Read,
+    destination: &Path,
+    created: &mut Vec<PathBuf>,
+) -> Result<(), String> {
+    let destination = destination.canonicalize().map_err(|e| e.to_string())?;
+    let mut archive = tar::Archive::new(archive);
+    archive.set_overwrite(false);
+    archive.set_preserve_permissions(false);
+    for entry in archive.entries().map_err(|e| e.to_string())? {
+        let mut entry = entry.map_err(|e| format!("damaged archive: {e}"))?;
+        let path = entry.path().map_err(|e| e.to_string())?.into_owned();
+        let refuse = |reason: &str| format!("refusing to extract {}: {reason}", path.display());
+        let kind = entry.header().entry_type();
+        if kind == tar::EntryType::XGlobalHeader {
+            continue;
+        }
+        if !stays_inside(&path) {
+            return Err(refuse("it would be written outside the destination"));
+        }
+        if kind.is_symlink() {
+            let target = entry
+                .link_name()
+                .map_err(|e| e.to_string())?
+                .ok_or(refuse("the link has no target"))?;
+            if !link_stays_inside(&destination, &path, &target) {
+                return Err(refuse("the link points outside the destination"));
+            }
+        } else if !(kind.is_file() || kind.is_dir()) {
+            return Err(refuse("only files, folders and links can be extracted"));
+        }
+
+        // note down everything this entry will create first, so a failure can take it back
+        let mut partial = destination.to_path_buf();
+        for component in path.components() {
+            partial.push(component);
+            if fs::symlink_metadata(&partial).is_err() {
+                created.push(partial.clone());
+            }
+        }
+        let unpacked = entry
+            .unpack_in(&destination)
+            .map_err(|e| format!("couldn't extract {}: {e}", path.display()))?;
+        if !unpacked {
+            return Err(refuse("it would be written outside the destination"));
+        }
+    }
+    // tar stops at the end-of-archive marker, but age only finds out the file was cut short once its
+    // last chunk is read, so read whatever is left
+    io::copy(&mut archive.into_inner(), &mut io::sink())
+        .map_err(|e| format!("decryption failed: {e}"))?;
+    Ok(())
+}
+
+/// decrypt an archive made by `encrypt_directories` and extract it into `destination`. `count`
+/// goes up with every byte of `input` read
+pub fn extract_archive(
+    identity: &WildcardIdentity,
+    input: &Path,
+    armor: bool,
+    destination: &Path,
+    count: Arc<AtomicU64>,
+) -> Result<(), String> {
+    let file = if armor {
+        open_armored(input)?
+    } else {
+        File::open(input).map_err(|e| e.to_string())?
+    };
+    let reader = age::armor::ArmoredReader::new(BufReader::new(Counted { inner: file, count }));
+    let decryptor = age::Decryptor::new_buffered(reader).map_err(|e| e.to_string())?;
+    let decrypted = with_identities(identity, |identities| decryptor.decrypt(identities))
+        .map_err(|e| format!("decryption failed: {e}"))?;
+
+    let mut decrypted = BufReader::new(decrypted);
+    let compressed = decrypted
+        .fill_buf()
+        .map_err(|e| format!("decryption failed: {e}"))?
+        .starts_with(&ZSTD_MAGIC);
+    let archive: Box<dyn Read> = if compressed {
+        Box::new(zstd::Decoder::with_buffer(decrypted).map_err(|e| e.to_string())?)
+    } else {
+        Box::new(decrypted)
+    };
+
+    let mut created = vec![];
+    let result = unpack(archive, destination, &mut created);
+    if result.is_err() {
+        // newest first, so folders are empty by the time they're removed
+        for path in created.iter().rev() {
+            let _ = match fs::symlink_metadata(path) {
+                Ok(metadata) if metadata.is_dir() => fs::remove_dir(path),
+                _ => fs::remove_file(path),
+            };
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// a tar archive of empty folders and links
+    fn tar_of(dirs: &[&str], links: &[(&str, &str)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(vec![]);
+        for dir in dirs {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Directory);
+            header.set_mode(0o755);
+            header.set_size(0);
+            builder.append_data(&mut header, dir, io::empty()).unwrap();
+        }
+        for (path, target) in links {
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Symlink);
+            header.set_size(0);
+            builder.append_link(&mut header, path, target).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    #[test]
+    fn refuses_links_that_escape_through_other_links() {
+        let destination = tempfile::tempdir().unwrap();
+        let archive = tar_of(&["a"], &[("a/up", ".."), ("a/up2", "up/..")]);
+        let mut created = vec![];
+        let result = unpack(archive.as_slice(), destination.path(), &mut created);
+        assert!(result.unwrap_err().contains("a/up2"));
+        assert!(fs::symlink_metadata(destination.path().join("a/up2")).is_err());
+    }
+
+    #[test]
+    fn refuses_parent_dir_after_something_missing() {
+        let destination = tempfile::tempdir().unwrap();
+        // `a/b` could be extracted as `a/b -> ..` afterwards, which would make this `..` escape
+        let archive = tar_of(&["a"], &[("a/l", "b/../.."), ("a/b", "..")]);
+        let result = unpack(archive.as_slice(), destination.path(), &mut vec![]);
+        assert!(result.unwrap_err().contains("a/l"));
+    }
+
+    #[test]
+    fn extracts_links_that_stay_inside() {
+        let destination = tempfile::tempdir().unwrap();
+        let archive = tar_of(
+            &["a", "a/bin", "a/pkg"],
+            &[("a/bin/tool", "../pkg/tool"), ("a/up", "..")],
+        );
+        unpack(archive.as_slice(), destination.path(), &mut vec![]).unwrap();
+        assert_eq!(
+            fs::read_link(destination.path().join("a/bin/tool")).unwrap(),
+            Path::new("../pkg/tool")
+        );
+    }
+
+    #[test]
+    fn takes_back_an_archive_whose_ciphertext_was_cut_short() {
+        // everything up to the first end-of-archive block fits the first 64 KiB chunk, so tar never
+        // reads the last one, which holds only the second block
+        let mut builder = tar::Builder::new(vec![]);
+        let mut header = tar::Header::new_gnu();
+        header.set_mode(0o644);
+        header.set_size(64 * 1024 - 1024);
+        let data = vec![b'a'; 64 * 1024 - 1024];
+        builder
+            .append_data(&mut header, "a.txt", data.as_slice())
+            .unwrap();
+        let archive = builder.into_inner().unwrap();
+        assert_eq!(archive.len(), 64 * 1024 + 512);
+
+        let identity = age::x25519::Identity::generate();
+        let recipient = identity.to_public();
+        let encryptor = age::Encryptor::with_recipients(std::iter::once(&recipient as _)).unwrap();
+        let mut writer = encryptor.wrap_output(vec![]).unwrap();
+        writer.write_all(&archive).unwrap();
+        let mut encrypted = writer.finish().unwrap();
+        encrypted.truncate(encrypted.len() - 100);
+
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("folder.tar.age");
+        fs::write(&input, encrypted).unwrap();
+        let destination = dir.path().join("out");
+        fs::create_dir(&destination).unwrap();
+        let result = extract_archive(
+            &WildcardIdentity::X25519(identity),
+            &input,
+            false,
+            &destination,
+            Arc::new(AtomicU64::new(0)),
+        );
+        assert!(result.unwrap_err().starts_with("decryption failed"));
+        assert!(fs::symlink_metadata(destination.join("a.txt")).is_err());
+    }
+
+    #[test]
+    fn refuses_absolute_links() {
+        let destination = tempfile::tempdir().unwrap();
+        let archive = tar_of(&[], &[("etc", "/etc")]);
+        assert!(unpack(archive.as_slice(), destination.path(), &mut vec![]).is_err());
+    }
+}
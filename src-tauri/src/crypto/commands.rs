@@ -1,13 +1,15 @@
-use crate::crypto::{self, WildcardIdentity, WildcardRecipient};
+use crate::crypto::archive;
+use crate::crypto::output::{self, ConflictPolicy, OutputKind, OutputOptions, PendingOutput};
+use crate::crypto::{self, ssh, WildcardIdentity, WildcardRecipient};
+use crate::store::unix_time;
 use crate::AppState;
-use age_xwing::{HybridIdentity, HybridRecipient};
+use age_xwing::HybridRecipient;
 use futures_util::future::join_all;
 use rand::seq::IndexedRandom;
 use secrecy::zeroize::Zeroizing;
-use secrecy::ExposeSecret;
 use secrecy::SecretString;
 use serde::{Deserialize, Serialize};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::path::PathBuf;
 use std::str::FromStr;
 use std::sync::atomic::{AtomicU64, Ordering};
@@ -18,6 +20,9 @@ use tokio::io::AsyncReadExt;
 use tokio::time;
 
 const WORDLIST: &str = include_str!("wordlists/eff_large_wordlist.txt");
+const ARMOR_BEGIN: &str = "-----BEGIN AGE ENCRYPTED FILE-----";
+/// how far into a file to look for the armor marker, past any whitespace in front of it
+pub const ARMOR_SEARCH_LIMIT: u64 = 64 * 1024;
 
 #[derive(serde::Serialize, specta::Type)]
 pub struct FileOperationProgress {
@@ -42,63 +47,97 @@ pub enum DecryptionMethod {
 #[tauri::command]
 #[specta::specta]
 pub async fn validate_key_text(text: String) -> Result<(), String> {
-    match bech32::decode(&text) {
-        Ok(_) => Ok(()),
-        Err(err) => Err(format!("this is not a valid age key. {err}")),
+    // a single key, an identity file with several keys and `#` comments, or ssh keys
+    let mut keys = 0;
+    let mut in_ssh_key = false;
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if in_ssh_key {
+            in_ssh_key = line != ssh::PEM_END;
+            continue;
+        }
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        keys += 1;
+        if line == ssh::PEM_BEGIN {
+            in_ssh_key = true;
+            continue;
+        }
+        if ssh::parse_public_line(line).is_some() {
+            continue;
+        }
+        if let Err(err) = bech32::decode(line) {
+            return Err(format!("line {} is not a valid age key. {err}", number + 1));
+        }
     }
+    if keys == 0 {
+        return Err("this doesn't contain any age keys".to_string());
+    }
+    Ok(())
+}
+
+/// where the armor marker starts in `data`, after any byte order mark and whitespace (blank lines
+/// with CRLF endings included). `None` if it isn't armored
+pub fn armor_start(data: &[u8]) -> Option<usize> {
+    let bom = if data.starts_with(b"\xEF\xBB\xBF") {
+        3
+    } else {
+        0
+    };
+    let start = bom
+        + data[bom..]
+            .iter()
+            .take_while(|byte| byte.is_ascii_whitespace())
+            .count();
+    data[start..]
+        .starts_with(ARMOR_BEGIN.as_bytes())
+        .then_some(start)
 }
 
 #[tauri::command]
 #[specta::specta]
 pub fn armor_check_text(text: String) -> bool {
-    text.starts_with("-----BEGIN AGE ENCRYPTED FILE-----")
+    armor_start(text.as_bytes()).is_some()
 }
 
 pub async fn armor_check_file(path: &String) -> Result<bool, String> {
-    let mut file = tokio::fs::File::open(&path)
+    let file = tokio::fs::File::open(&path)
         .await
         .map_err(|err| format!("could not open file: {err}"))?;
-    let mut buf = [0u8; 34];
-    let bytes = file
-        .read(&mut buf)
+    // binary age files aren't valid UTF-8, so this works on the raw bytes
+    let mut prefix = vec![];
+    file.take(ARMOR_SEARCH_LIMIT)
+        .read_to_end(&mut prefix)
         .await
         .map_err(|err| format!("could not read file: {err}"))?;
-    let key_text = String::from_utf8(buf[..bytes].to_vec())
-        .map_err(|err| format!("could not decode text content: {err}"))?;
-    Ok(armor_check_text(key_text))
+    Ok(armor_start(&prefix).is_some())
 }
 
 #[tauri::command]
 #[specta::specta]
 pub async fn validate_key_file(path: String) -> Result<(), String> {
-    let mut file = tokio::fs::File::open(&path)
+    let file = tokio::fs::File::open(&path)
         .await
         .map_err(|err| format!("could not open file: {err}"))?;
-    let mut buf = [0u8; 100];
-    let bytes = file
-        .read(&mut buf)
+    let mut buf = vec![];
+    file.take(1024 * 1024)
+        .read_to_end(&mut buf)
         .await
         .map_err(|err| format!("could not read file: {err}"))?;
-    let key_text = String::from_utf8(buf[..bytes].to_vec())
-        .map_err(|err| format!("could not decode text content: {err}"))?;
+    let key_text =
+        String::from_utf8(buf).map_err(|err| format!("could not decode text content: {err}"))?;
     validate_key_text(key_text).await
 }
 
-#[tauri::command]
-#[specta::specta]
-pub async fn encrypt_text(
+/// turn an `EncryptionMethod` into age recipients. vault keys are refused if they've expired
+fn resolve_recipients(
     recipient: EncryptionMethod,
-    text: String,
-    state: tauri::State<'_, AppState>,
-) -> Result<String, String> {
+    state: &AppState,
+) -> Result<Vec<WildcardRecipient>, String> {
     let recipients: Vec<WildcardRecipient> = match recipient {
         EncryptionMethod::X25519(public_keys) => {
-            let key_contents = state.with_vault(|vault| {
-                public_keys
-                    .iter()
-                    .map(|key| vault.get_key(key).unwrap().contents.public.clone())
-                    .collect::<Vec<String>>()
-            })?;
+            let key_contents = state.with_vault(|vault| vault.recipient_keys(&public_keys))??;
             let should_encrypt_pq = key_contents.iter().all(|key| key.starts_with("age1pq"));
             let mut recipients: Vec<WildcardRecipient> = Vec::with_capacity(key_contents.len());
             for key in key_contents {
@@ -112,10 +151,10 @@ pub async fn encrypt_text(
                     };
                     recipients.push(recipient);
                 } else {
-                    recipients.push(WildcardRecipient::X25519(
-                        age::x25519::Recipient::from_str(key.as_str())
-                            .map_err(|e| format!("invalid key: {}", e.to_string()))?,
-                    ))
+                    recipients.push(
+                        WildcardRecipient::from_public(&key)
+                            .map_err(|e| format!("invalid key: {e}"))?,
+                    )
                 }
             }
             recipients
@@ -126,6 +165,39 @@ pub async fn encrypt_text(
             ))]
         }
     };
+    Ok(recipients)
+}
+
+/// turn a `DecryptionMethod` into an age identity. for vault keys, `private_key` is the key's id
+fn resolve_identity(
+    private_key: String,
+    method: DecryptionMethod,
+    state: &AppState,
+) -> Result<WildcardIdentity, String> {
+    Ok(match method {
+        DecryptionMethod::X25519 => {
+            let key_content = state.with_vault(|vault| {
+                let key_metadata = vault
+                    .get_key(&private_key)
+                    .ok_or("key does not exist".to_string())?;
+                vault.decrypt_private_key(key_metadata)
+            })??;
+            WildcardIdentity::from_secret(key_content)?
+        }
+        DecryptionMethod::Scrypt => {
+            WildcardIdentity::Scrypt(age::scrypt::Identity::new(SecretString::from(private_key)))
+        }
+    })
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn encrypt_text(
+    recipient: EncryptionMethod,
+    text: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<String, String> {
+    let recipients = Arc::new(resolve_recipients(recipient, &state)?);
     return crypto::encrypt_armored_text(&recipients, text).await;
 }
 
@@ -137,34 +209,7 @@ pub async fn decrypt_text(
     method: DecryptionMethod,
     state: tauri::State<'_, AppState>,
 ) -> Result<String, String> {
-    let identity = match method {
-        DecryptionMethod::X25519 => {
-            let key_content = state
-                .with_vault(|vault| {
-                    let key_metadata = vault.get_key(&private_key).unwrap();
-                    vault
-                        .decrypt_secret(&key_metadata.contents.private.as_ref().unwrap())
-                        .clone()
-                })?
-                .map_err(|e| e.to_string())?;
-
-            if key_content
-                .expose_secret()
-                .starts_with("AGE-SECRET-KEY-PQ-")
-            {
-                WildcardIdentity::Hybrid(HybridIdentity::from_string(key_content)?)
-            } else {
-                WildcardIdentity::X25519(
-                    key_content
-                        .expose_secret()
-                        .parse::<age::x25519::Identity>()?,
-                )
-            }
-        }
-        DecryptionMethod::Scrypt => {
-            WildcardIdentity::Scrypt(age::scrypt::Identity::new(SecretString::from(private_key)))
-        }
-    };
+    let identity = Arc::new(resolve_identity(private_key, method, &state)?);
 
     return crypto::decrypt_armored_text(&identity, text).await;
 }
@@ -177,43 +222,14 @@ pub async fn encrypt_file(
     files: Vec<String>,
     state: tauri::State<'_, AppState>,
     armor: Option<bool>,
+    output: Option<OutputOptions>,
 ) -> Result<(), String> {
     let armor = armor.unwrap_or(false);
-    let recipients: Vec<WildcardRecipient> = match recipient {
-        EncryptionMethod::X25519(public_keys) => {
-            let key_contents = state.with_vault(|vault| {
-                public_keys
-                    .iter()
-                    .map(|key| vault.get_key(key).unwrap().contents.public.clone())
-                    .collect::<Vec<String>>()
-            })?;
-            let should_encrypt_pq = key_contents.iter().all(|key| key.starts_with("age1pq"));
-            let mut recipients: Vec<WildcardRecipient> = Vec::with_capacity(key_contents.len());
-            for key in key_contents {
-                if key.starts_with("age1pq") {
-                    let hybrid_recipient = HybridRecipient::from_string(&key)
-                        .map_err(|e| format!("invalid key: {}", e.to_string()))?;
-                    let recipient = if should_encrypt_pq {
-                        WildcardRecipient::Hybrid(hybrid_recipient)
-                    } else {
-                        WildcardRecipient::X25519(hybrid_recipient.to_x25519())
-                    };
-                    recipients.push(recipient);
-                } else {
-                    recipients.push(WildcardRecipient::X25519(
-                        age::x25519::Recipient::from_str(key.as_str())
-                            .map_err(|e| format!("invalid key: {}", e.to_string()))?,
-                    ))
-                }
-            }
-            recipients
-        }
-        EncryptionMethod::Scrypt(password) => {
-            vec![WildcardRecipient::Scrypt(age::scrypt::Recipient::new(
-                SecretString::from(password),
-            ))]
-        }
-    };
+    let recipients = Arc::new(resolve_recipients(recipient, &state)?);
+    let inputs: Vec<PathBuf> = files.iter().map(PathBuf::from).collect();
+    let output = output.unwrap_or_default();
+    let overwrite = output.on_conflict == Some(ConflictPolicy::Overwrite);
+    let outputs = output::output_paths(&inputs, &output, OutputKind::Encrypted)?;
     let file_sizes: HashMap<String, u64> = files
         .clone()
         .into_iter()
@@ -229,42 +245,37 @@ pub async fn encrypt_file(
         )
         .collect();
     let total_bytes: u64 = file_sizes.values().sum();
-    let total_read_bytes_ptr = Arc::new(AtomicU64::new(0));
-    let reader_ptr = Arc::new(reader);
+    let total_read_bytes = Arc::new(AtomicU64::new(0));
     let mut output_paths = Vec::new();
-    let cooldown = time::Duration::from_millis(100);
-    for file in files {
-        let total_read_bytes = total_read_bytes_ptr.clone();
+    for (file, output_path) in files.into_iter().zip(outputs) {
         let path = PathBuf::from(file.clone());
-        let reader = reader_ptr.clone();
-        let mut progress_interval = time::interval(cooldown);
-        let progress_task = tauri::async_runtime::spawn(async move {
-            loop {
-                let _ = reader.send(FileOperationProgress {
-                    // its okay if it doesnt send i'd rather the files just encrypt
-                    read_bytes: total_read_bytes.load(Ordering::SeqCst),
-                    total_bytes,
-                    current_file: path.file_name().unwrap().to_str().unwrap().to_string(),
-                });
-                progress_interval.tick().await;
-            }
-        });
-
-        let total_read_bytes = total_read_bytes_ptr.clone();
-        let path = PathBuf::from(file.clone());
-        let output_path =
-            crypto::encrypt_file(&recipients, &path.clone(), armor, move |processed_bytes| {
-                total_read_bytes
-                    .fetch_add(processed_bytes as u64, std::sync::atomic::Ordering::SeqCst);
-            })
-            .await
-            .map_err(|e| e.to_string())?;
+        let current_file = path.file_name().unwrap().to_str().unwrap().to_string();
+        let progress_task = report_progress(
+            reader.clone(),
+            total_read_bytes.clone(),
+            total_bytes,
+            current_file.clone(),
+        );
+        let read_bytes = total_read_bytes.clone();
+        let result = crypto::encrypt_file(
+            &recipients,
+            &path,
+            &output_path,
+            overwrite,
+            armor,
+            move |processed_bytes| {
+                read_bytes.fetch_add(processed_bytes as u64, Ordering::SeqCst);
+            },
+        )
+        .await;
+        // stopped before anything can return, or it would keep sending forever
         progress_task.abort();
-        let _ = reader_ptr.clone().send(FileOperationProgress {
+        result?;
+        let _ = reader.send(FileOperationProgress {
             // its okay if it doesnt send i'd rather the files just encrypt
             read_bytes: *file_sizes.get(&file).unwrap(),
             total_bytes,
-            current_file: path.file_name().unwrap().to_str().unwrap().to_string(),
+            current_file,
         });
         output_paths.push(output_path)
     }
@@ -280,35 +291,13 @@ pub async fn decrypt_file(
     files: Vec<String>,
     method: DecryptionMethod,
     state: tauri::State<'_, AppState>,
+    output: Option<OutputOptions>,
 ) -> Result<(), String> {
-    let identity = match method {
-        DecryptionMethod::X25519 => {
-            let key_content = state
-                .with_vault(|vault| {
-                    let key_metadata = vault.get_key(&private_key).unwrap();
-                    vault
-                        .decrypt_secret(&key_metadata.contents.private.as_ref().unwrap())
-                        .clone()
-                })?
-                .map_err(|e| e.to_string())?;
-
-            if key_content
-                .expose_secret()
-                .starts_with("AGE-SECRET-KEY-PQ-")
-            {
-                WildcardIdentity::Hybrid(HybridIdentity::from_string(key_content)?)
-            } else {
-                WildcardIdentity::X25519(
-                    key_content
-                        .expose_secret()
-                        .parse::<age::x25519::Identity>()?,
-                )
-            }
-        }
-        DecryptionMethod::Scrypt => {
-            WildcardIdentity::Scrypt(age::scrypt::Identity::new(SecretString::from(private_key)))
-        }
-    };
+    let inputs: Vec<PathBuf> = files.iter().map(PathBuf::from).collect();
+    let output = output.unwrap_or_default();
+    let overwrite = output.on_conflict == Some(ConflictPolicy::Overwrite);
+    let outputs = output::output_paths(&inputs, &output, OutputKind::Decrypted)?;
+    let identity = Arc::new(resolve_identity(private_key, method, &state)?);
     let file_sizes: HashMap<String, u64> = files
         .clone()
         .into_iter()
@@ -324,45 +313,40 @@ pub async fn decrypt_file(
         )
         .collect();
     let total_bytes: u64 = file_sizes.values().sum();
-    let total_read_bytes_ptr = Arc::new(AtomicU64::new(0));
-    let reader_ptr = Arc::new(reader);
+    let total_read_bytes = Arc::new(AtomicU64::new(0));
     let mut output_paths = Vec::new();
-    let cooldown = time::Duration::from_millis(100);
-    for file in files {
-        let total_read_bytes = total_read_bytes_ptr.clone();
-        let reader = reader_ptr.clone();
-        let path_ptr = Arc::new(PathBuf::from_str(&file).unwrap());
-        let path = path_ptr.clone();
-        let mut progress_interval = time::interval(cooldown);
-        let progress_task = tauri::async_runtime::spawn(async move {
-            loop {
-                progress_interval.tick().await;
-                let _ = reader.send(FileOperationProgress {
-                    // its okay if it doesnt send i'd rather the files just encrypt
-                    read_bytes: total_read_bytes.load(Ordering::SeqCst),
-                    total_bytes,
-                    current_file: path.file_name().unwrap().to_str().unwrap().to_string(),
-                });
-            }
-        });
+    for (file, output_path) in files.into_iter().zip(outputs) {
         let is_armored = armor_check_file(&file).await?;
-        let total_read_bytes = total_read_bytes_ptr.clone();
-        let path = path_ptr;
-        let output_path =
-            crypto::decrypt_file(&identity, &path, is_armored, move |processed_bytes| {
-                total_read_bytes.fetch_add(processed_bytes as u64, Ordering::SeqCst);
-            })
-            .await?;
+        let path = PathBuf::from_str(&file).unwrap();
+        let current_file = path.file_name().unwrap().to_str().unwrap().to_string();
+        let progress_task = report_progress(
+            reader.clone(),
+            total_read_bytes.clone(),
+            total_bytes,
+            current_file.clone(),
+        );
+        let read_bytes = total_read_bytes.clone();
+        let result = crypto::decrypt_file(
+            &identity,
+            &path,
+            &output_path,
+            overwrite,
+            is_armored,
+            move |processed_bytes| {
+                read_bytes.fetch_add(processed_bytes as u64, Ordering::SeqCst);
+            },
+        )
+        .await;
         progress_task.abort();
-        let reader = reader_ptr.clone();
+        result?;
         let _ = reader.send(FileOperationProgress {
             read_bytes: *file_sizes.get(&file).unwrap(),
             total_bytes,
-            current_file: path.file_name().unwrap().to_str().unwrap().to_string(),
+            current_file,
         }); // ensure that it "completes" on the frontend
         output_paths.push(output_path)
     }
-    let _ = reader_ptr.send(FileOperationProgress {
+    let _ = reader.send(FileOperationProgress {
         read_bytes: total_bytes,
         total_bytes,
         current_file: "".to_string(),
@@ -371,6 +355,203 @@ pub async fn decrypt_file(
     Ok(())
 }
 
+/// send progress every 100ms until aborted
+fn report_progress(
+    reader: tauri::ipc::Channel<FileOperationProgress>,
+    read_bytes: Arc<AtomicU64>,
+    total_bytes: u64,
+    current_file: String,
+) -> tauri::async_runtime::JoinHandle<()> {
+    let mut progress_interval = time::interval(time::Duration::from_millis(100));
+    tauri::async_runtime::spawn(async move {
+        loop {
+            progress_interval.tick().await;
+            let _ = reader.send(FileOperationProgress {
+                read_bytes: read_bytes.load(Ordering::SeqCst).min(total_bytes),
+                total_bytes,
+                current_file: current_file.clone(),
+            });
+        }
+    })
+}
+
+/// pack whole folders into one tar archive, optionally compressed with zstd, and encrypt it into a
+/// single file. it's all one stream, so nothing unencrypted is written anywhere
+#[tauri::command]
+#[specta::specta]
+pub async fn encrypt_directories(
+    recipient: EncryptionMethod,
+    reader: tauri::ipc::Channel<FileOperationProgress>,
+    directories: Vec<String>,
+    state: tauri::State<'_, AppState>,
+    armor: Option<bool>,
+    compress: Option<bool>,
+    output: Option<OutputOptions>,
+) -> Result<(), String> {
+    let armor = armor.unwrap_or(false);
+    let compress = compress.unwrap_or(false);
+    let recipients = resolve_recipients(recipient, &state)?;
+    let directories: Vec<PathBuf> = directories.iter().map(PathBuf::from).collect();
+    if directories.is_empty() {
+        return Err("no folders chosen".to_string());
+    }
+    let mut names = HashSet::new();
+    for directory in &directories {
+        if !directory.is_dir() {
+            return Err(format!("{} is not a folder", directory.display()));
+        }
+        let name = directory
+            .file_name()
+            .ok_or(format!("{} can't be archived", directory.display()))?;
+        // each folder goes in the archive under its own name
+        if !names.insert(name.to_os_string()) {
+            return Err(format!(
+                "more than one folder is called {}. archive them separately",
+                name.to_string_lossy()
+            ));
+        }
+    }
+
+    let kind = if compress {
+        OutputKind::CompressedArchive
+    } else {
+        OutputKind::Archive
+    };
+    let output = output.unwrap_or_default();
+    let overwrite = output.on_conflict == Some(ConflictPolicy::Overwrite);
+    let output_path =
+        output::output_paths(&[archive::archive_input(&directories)], &output, kind)?.remove(0);
+    let output_directory = output_path
+        .parent()
+        .and_then(|parent| parent.canonicalize().ok())
+        .unwrap_or_default();
+    if directories.iter().any(|directory| {
+        directory
+            .canonicalize()
+            .is_ok_and(|directory| output_directory.starts_with(directory))
+    }) {
+        return Err("the archive can't be saved inside a folder that's being archived".to_string());
+    }
+
+    let sizes = directories.clone();
+    let total_bytes = tauri::async_runtime::spawn_blocking(move || {
+        sizes
+            .iter()
+            .map(|directory| archive::tar_size(directory))
+            .sum::<std::io::Result<u64>>()
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| format!("couldn't read folder: {e}"))?;
+    let current_file = output_path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+
+    let (pending_output, file) = PendingOutput::create(&output_path, overwrite).await?;
+    let file = file.into_std().await;
+    let read_bytes = Arc::new(AtomicU64::new(0));
+    let progress_task = report_progress(
+        reader.clone(),
+        read_bytes.clone(),
+        total_bytes,
+        current_file.clone(),
+    );
+    let result = tauri::async_runtime::spawn_blocking(move || {
+        archive::encrypt_directories(&recipients, &directories, file, armor, compress, read_bytes)
+    })
+    .await
+    .map_err(|e| e.to_string());
+    progress_task.abort();
+    result??;
+    pending_output.persist().await?;
+    let _ = reader.send(FileOperationProgress {
+        read_bytes: total_bytes,
+        total_bytes,
+        current_file,
+    }); // ensure that it "completes" on the frontend
+    reveal_items_in_dir(vec![output_path]).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// decrypt an archive made by `encrypt_directories` and extract it into `destination`. entries that
+/// would land outside it, or links pointing out of it, stop the extraction, and anything already
+/// extracted is removed again
+#[tauri::command]
+#[specta::specta]
+pub async fn decrypt_archive(
+    private_key: String,
+    reader: tauri::ipc::Channel<FileOperationProgress>,
+    file: String,
+    method: DecryptionMethod,
+    destination: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), String> {
+    let identity = resolve_identity(private_key, method, &state)?;
+    let destination = PathBuf::from(destination);
+    if !destination.is_dir() {
+        return Err(format!(
+            "the destination {} does not exist",
+            destination.display()
+        ));
+    }
+    let is_armored = armor_check_file(&file).await?;
+    let total_bytes = metadata(&file).await.map_err(|e| e.to_string())?.len();
+    let path = PathBuf::from(&file);
+    let current_file = path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string();
+
+    let read_bytes = Arc::new(AtomicU64::new(0));
+    let progress_task = report_progress(
+        reader.clone(),
+        read_bytes.clone(),
+        total_bytes,
+        current_file.clone(),
+    );
+    let extract_to = destination.clone();
+    let result = tauri::async_runtime::spawn_blocking(move || {
+        archive::extract_archive(&identity, &path, is_armored, &extract_to, read_bytes)
+    })
+    .await
+    .map_err(|e| e.to_string());
+    progress_task.abort();
+    result??;
+    let _ = reader.send(FileOperationProgress {
+        read_bytes: total_bytes,
+        total_bytes,
+        current_file,
+    }); // ensure that it "completes" on the frontend
+    reveal_items_in_dir(vec![destination]).map_err(|e| e.to_string())?;
+    Ok(())
+}
+
+/// the outputs of an `encrypt_file`, `decrypt_file` or `encrypt_directories` call that already
+/// exist. the frontend asks before sending `ConflictPolicy::Overwrite` for these
+#[tauri::command]
+#[specta::specta]
+pub fn check_output_conflicts(
+    files: Vec<String>,
+    output: Option<OutputOptions>,
+    kind: OutputKind,
+) -> Result<Vec<String>, String> {
+    let inputs: Vec<PathBuf> = files.iter().map(PathBuf::from).collect();
+    let inputs = match kind {
+        OutputKind::Archive | OutputKind::CompressedArchive => {
+            vec![archive::archive_input(&inputs)]
+        }
+        _ => inputs,
+    };
+    let conflicts = output::conflicts(&inputs, &output.unwrap_or_default(), kind)?;
+    Ok(conflicts
+        .into_iter()
+        .map(|path| path.to_string_lossy().to_string())
+        .collect())
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, specta::Type)]
 pub enum KeyFormat {
     X25519,
@@ -382,16 +563,18 @@ pub enum KeyFormat {
 pub async fn generate_keypair(
     name: String,
     format: Option<KeyFormat>,
+    expires_at: Option<u64>,
     state: tauri::State<'_, AppState>,
 ) -> Result<(), String> {
     if name.len() == 0 {
         return Err("no name set".to_string());
     }
     state.with_vault(|vault| {
-        let keypair = match format {
+        let mut keypair = match format {
             Some(KeyFormat::X25519) => vault.generate_x25519_keypair(name),
             _ => vault.generate_keypair(name), // if none or if PostQuantum
         }?;
+        keypair.expires_at = expires_at.map(unix_time);
         vault.put_key(keypair)?;
         Ok::<(), String>(())
     })??;
@@ -399,6 +582,13 @@ pub async fn generate_keypair(
     Ok(())
 }
 
+/// reply to a `plugin-prompt` event
+#[tauri::command]
+#[specta::specta]
+pub fn answer_plugin_prompt(id: u32, answer: crypto::plugin::PluginAnswer) -> Result<(), String> {
+    crypto::plugin::answer(id, answer)
+}
+
 #[tauri::command]
 #[specta::specta]
 pub async fn downgrade_hybrid_public_key(public_key: String) -> Result<String, String> {
@@ -1,29 +1,58 @@
 // higher-level age functions to be called from the frontend
 
+pub mod archive;
 pub mod commands;
+pub mod output;
+pub mod plugin;
+pub mod ssh;
 use age::Decryptor;
 use age::{Identity, Recipient};
 use age_xwing::{HybridIdentity, HybridRecipient};
 pub use commands::*;
 use futures_util::{AsyncReadExt as FuturesReadExt, AsyncWriteExt as FuturesWriteExt};
-use secrecy::SecretString;
-use std::path::PathBuf;
+use secrecy::{ExposeSecret, SecretString};
+use ssh::SshIdentity;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
 use tokio::fs::File;
 use tokio::io::{AsyncReadExt, AsyncWriteExt, BufReader, BufWriter};
 use tokio_util::compat::{TokioAsyncReadCompatExt, TokioAsyncWriteCompatExt};
 
-/// an enum representing the x25519, hybrid, and scrypt age recipient types. directly implements `age::Recipient`.
+/// an enum representing the x25519, hybrid, ssh, plugin, and scrypt age recipient types. directly implements `age::Recipient`.
 pub enum WildcardRecipient {
     X25519(age::x25519::Recipient),
     Hybrid(HybridRecipient),
+    Ssh(age::ssh::Recipient),
+    Plugin(age::plugin::Recipient),
     Scrypt(age::scrypt::Recipient),
 }
 
 impl WildcardRecipient {
+    /// parse a public key as stored in the vault
+    pub fn from_public(public: &str) -> Result<WildcardRecipient, String> {
+        if public.starts_with("age1pq1") {
+            Ok(Self::Hybrid(HybridRecipient::from_string(public)?))
+        } else if public.starts_with("ssh-") {
+            Ok(Self::Ssh(ssh::parse_recipient(public)?))
+        } else {
+            match public.parse::<age::x25519::Recipient>() {
+                Ok(recipient) => Ok(Self::X25519(recipient)),
+                // plugin recipients look like `age1<plugin name>1...`
+                Err(error) => public
+                    .parse::<age::plugin::Recipient>()
+                    .map(Self::Plugin)
+                    .map_err(|_| error.to_string()),
+            }
+        }
+    }
+
     pub fn to_string(&self) -> Result<String, String> {
         Ok(match self {
             Self::Hybrid(recipient) => recipient.to_string(),
             Self::X25519(recipient) => recipient.to_string(),
+            Self::Ssh(recipient) => recipient.to_string(),
+            Self::Plugin(recipient) => recipient.to_string(),
             Self::Scrypt(_) => return Err("cannot convert scrypt identity to string".to_string()),
         })
     }
@@ -43,23 +72,49 @@ impl Recipient for WildcardRecipient {
         match self {
             Self::X25519(recipient) => recipient.wrap_file_key(file_key),
             Self::Hybrid(recipient) => recipient.wrap_file_key(file_key),
+            Self::Ssh(recipient) => recipient.wrap_file_key(file_key),
+            Self::Plugin(recipient) => plugin::recipient_plugin(recipient)?.wrap_file_key(file_key),
             Self::Scrypt(recipient) => recipient.wrap_file_key(file_key),
         }
     }
 }
 
-/// an enum representing the x25519, hybrid, and scrypt age identity types. directly implements `age::Identity`.
+/// an enum representing the x25519, hybrid, ssh, plugin, and scrypt age identity types. directly implements `age::Identity`.
 pub enum WildcardIdentity {
     X25519(age::x25519::Identity),
     Hybrid(HybridIdentity),
+    Ssh(SshIdentity),
+    Plugin(age::plugin::Identity),
     Scrypt(age::scrypt::Identity),
 }
 
 impl WildcardIdentity {
+    /// parse a private key as stored in the vault
+    pub fn from_secret(secret: SecretString) -> Result<WildcardIdentity, String> {
+        if secret.expose_secret().starts_with("AGE-SECRET-KEY-PQ-") {
+            Ok(Self::Hybrid(HybridIdentity::from_string(secret)?))
+        } else if ssh::is_ssh_secret(secret.expose_secret()) {
+            Ok(Self::Ssh(SshIdentity::from_secret(&secret)?))
+        } else if secret.expose_secret().starts_with("AGE-PLUGIN-") {
+            Ok(Self::Plugin(
+                secret.expose_secret().parse::<age::plugin::Identity>()?,
+            ))
+        } else {
+            Ok(Self::X25519(
+                secret.expose_secret().parse::<age::x25519::Identity>()?,
+            ))
+        }
+    }
+
     pub fn to_public(&self) -> Result<WildcardRecipient, String> {
         Ok(match self {
             Self::Hybrid(identity) => WildcardRecipient::Hybrid(identity.to_public()),
             Self::X25519(identity) => WildcardRecipient::X25519(identity.to_public()),
+            Self::Ssh(identity) => WildcardRecipient::Ssh(identity.to_public()?),
+            // only the plugin (and often its hardware) knows this
+            Self::Plugin(_) => {
+                return Err("cannot get the recipient of a plugin identity".to_string())
+            }
             Self::Scrypt(_) => return Err("cannot convert scrypt identity to public".to_string()),
         })
     }
@@ -68,6 +123,8 @@ impl WildcardIdentity {
         Ok(match self {
             Self::Hybrid(identity) => identity.to_string(),
             Self::X25519(identity) => SecretString::from(identity.to_string()),
+            Self::Ssh(identity) => identity.to_string(),
+            Self::Plugin(identity) => SecretString::from(identity.to_string()),
             Self::Scrypt(_) => return Err("cannot convert scrypt identity to string".to_string()),
         })
     }
@@ -81,19 +138,98 @@ impl Identity for WildcardIdentity {
         match self {
             Self::X25519(identity) => identity.unwrap_stanza(stanza),
             Self::Hybrid(identity) => identity.unwrap_stanza(stanza),
+            Self::Ssh(identity) => identity.unwrap_stanza(stanza),
+            Self::Plugin(_) => self.unwrap_stanzas(std::slice::from_ref(stanza)),
             Self::Scrypt(identity) => identity.unwrap_stanza(stanza),
         }
     }
+
+    fn unwrap_stanzas(
+        &self,
+        stanzas: &[age_core::format::Stanza],
+    ) -> Option<Result<age_core::format::FileKey, age::DecryptError>> {
+        match self {
+            // start the plugin once for the whole header, not once per stanza
+            Self::Plugin(identity) => match plugin::identity_plugin(identity) {
+                Ok(plugin) => plugin.unwrap_stanzas(stanzas),
+                Err(error) => Some(Err(error)),
+            },
+            // and ask for an ssh key's passphrase once
+            Self::Ssh(identity) => identity.unwrap_stanzas(stanzas),
+            _ => stanzas.iter().find_map(|stanza| self.unwrap_stanza(stanza)),
+        }
+    }
+}
+
+/// run `decrypt` with every identity that can open files for `identity`. that includes a hybrid
+/// key's x25519 half, which files are encrypted to when not every recipient is hybrid
+pub fn with_identities<T>(
+    identity: &WildcardIdentity,
+    decrypt: impl FnOnce(&mut dyn Iterator<Item = &dyn Identity>) -> T,
+) -> T {
+    match identity {
+        WildcardIdentity::Hybrid(hybrid_identity) => {
+            let x25519 = WildcardIdentity::X25519(hybrid_identity.to_x25519());
+            let mut identities = [identity as &dyn Identity, &x25519].into_iter();
+            decrypt(&mut identities)
+        }
+        _ => decrypt(&mut std::iter::once(identity as &dyn Identity)),
+    }
+}
+
+/// open an armored file, positioned at the armor marker. `ArmoredReader` needs the marker right at
+/// the start, so a BOM or whitespace in front of it is skipped
+pub fn open_armored(path: &Path) -> Result<std::fs::File, String> {
+    let mut file = std::fs::File::open(path).map_err(|e| e.to_string())?;
+    let mut prefix = vec![];
+    (&mut file)
+        .take(ARMOR_SEARCH_LIMIT)
+        .read_to_end(&mut prefix)
+        .map_err(|e| e.to_string())?;
+    let start = armor_start(&prefix).ok_or("not an armored age file".to_string())?;
+    file.seek(SeekFrom::Start(start as u64))
+        .map_err(|e| e.to_string())?;
+    Ok(file)
+}
+
+/// wrap a new file key to every recipient. plugins can wait minutes for a touch or a PIN while
+/// doing this, so it runs on a blocking thread instead of holding up the async runtime
+pub async fn encryptor(recipients: Arc<Vec<WildcardRecipient>>) -> Result<age::Encryptor, String> {
+    tauri::async_runtime::spawn_blocking(move || {
+        age::Encryptor::with_recipients(recipients.iter().map(|recipient| recipient as _))
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| e.to_string())
+}
+
+/// unwrap the file key with `identity` and start decrypting. like `encryptor`, this runs on a
+/// blocking thread, since a plugin identity may be waiting on the user
+pub async fn decrypt_async<R>(
+    decryptor: Decryptor<R>,
+    identity: Arc<WildcardIdentity>,
+) -> Result<age::stream::StreamReader<R>, String>
+where
+    R: futures_io::AsyncBufRead + Unpin + Send + 'static,
+{
+    tauri::async_runtime::spawn_blocking(move || {
+        with_identities(&identity, |identities| decryptor.decrypt_async(identities))
+    })
+    .await
+    .map_err(|e| e.to_string())?
+    .map_err(|e| e.to_string())
 }
 
 const MEGABYTE: usize = 1024 * 1024;
-/// every time a new chunk is encrypted, the callback will be run with the amount of bytes that were encrypted
+/// encrypt `file_path` to `output_path`. every time a new chunk is encrypted, the callback will be run with the amount of bytes that were encrypted
 pub async fn encrypt_file<F>(
-    recipients: &Vec<WildcardRecipient>,
+    recipients: &Arc<Vec<WildcardRecipient>>,
     file_path: &PathBuf,
+    output_path: &Path,
+    overwrite: bool,
     armor: bool,
     mut callback: F,
-) -> Result<PathBuf, String>
+) -> Result<(), String>
 where
     // im the greatest rust programmer ever
     F: FnMut(usize) + Send,
@@ -101,11 +237,8 @@ where
     let file = File::open(file_path).await.map_err(|e| e.to_string())?;
     let mut reader = BufReader::new(file);
 
-    let mut encrypted_output = file_path.clone();
-    encrypted_output.add_extension("age");
-    let output = File::create(&encrypted_output)
-        .await
-        .map_err(|e| e.to_string())?;
+    // removed again if anything below fails
+    let (pending_output, output) = output::PendingOutput::create(output_path, overwrite).await?;
     let format = if armor {
         age::armor::Format::AsciiArmor
     } else {
@@ -114,10 +247,7 @@ where
     let file_writer =
         age::armor::ArmoredWriter::wrap_async_output(BufWriter::new(output).compat_write(), format);
 
-    let encryptor = age::Encryptor::with_recipients(
-        recipients.iter().map(|recipient| recipient as _), // bro wtf
-    )
-    .map_err(|e| e.to_string())?;
+    let encryptor = encryptor(recipients.clone()).await?;
 
     let mut writer = encryptor
         .wrap_async_output(file_writer)
@@ -139,29 +269,22 @@ where
     }
 
     writer.close().await.map_err(|e| e.to_string())?;
-    Ok(encrypted_output)
+    drop(writer);
+    pending_output.persist().await
 }
 
 pub async fn decrypt_armored_text(
-    identity: &WildcardIdentity,
+    identity: &Arc<WildcardIdentity>,
     text: String,
 ) -> Result<String, String> {
+    let mut text = text.into_bytes();
+    text.drain(..armor_start(&text).unwrap_or(0));
     let decryptor = Decryptor::new_async_buffered(age::armor::ArmoredReader::from_async_reader(
-        &text.as_bytes()[..],
+        futures_util::io::Cursor::new(text),
     ))
     .await
     .map_err(|e| e.to_string())?;
-    let mut reader = match identity {
-        WildcardIdentity::Hybrid(hybrid_identity) => decryptor.decrypt_async(
-            vec![
-                identity as _,
-                &WildcardIdentity::X25519(hybrid_identity.to_x25519()) as _,
-            ]
-            .into_iter(),
-        ),
-        _ => decryptor.decrypt_async(std::iter::once(identity as _)),
-    }
-    .map_err(|e| e.to_string())?;
+    let mut reader = decrypt_async(decryptor, identity.clone()).await?;
     let mut decrypted = vec![];
     reader
         .read_to_end(&mut decrypted)
@@ -171,14 +294,11 @@ pub async fn decrypt_armored_text(
 }
 
 pub async fn encrypt_armored_text(
-    recipients: &Vec<WildcardRecipient>,
+    recipients: &Arc<Vec<WildcardRecipient>>,
     text: String,
 ) -> Result<String, String> {
     let mut encrypted = vec![];
-    let encryptor = age::Encryptor::with_recipients(
-        recipients.iter().map(|recipient| recipient as _), // bro wtf
-    )
-    .map_err(|e| e.to_string())?;
+    let encryptor = encryptor(recipients.clone()).await?;
     let mut writer = age::armor::ArmoredWriter::wrap_async_output(
         &mut encrypted,
         age::armor::Format::AsciiArmor,
@@ -195,27 +315,29 @@ pub async fn encrypt_armored_text(
     Ok(String::from_utf8(encrypted).map_err(|e| e.to_string())?)
 }
 
+/// decrypt `file_path` to `output_path`
 pub async fn decrypt_file<F>(
-    identity: &WildcardIdentity,
+    identity: &Arc<WildcardIdentity>,
     file_path: &PathBuf,
+    output_path: &Path,
+    overwrite: bool,
     armor: bool,
     mut callback: F,
-) -> Result<PathBuf, String>
+) -> Result<(), String>
 where
     F: FnMut(usize) + Send,
 {
-    let mut file = File::open(file_path).await.map_err(|e| e.to_string())?;
-    let file_size = file.metadata().await.map_err(|e| e.to_string())?.len() as usize;
-    if armor && file_size > MEGABYTE * 100 {
-        return Err("armored files over 100 MB are not supported".to_string());
-    }
-    let mut contents = Vec::with_capacity(MEGABYTE * if armor { 100 } else { 0 }); // don't allocate anything if not necessary
+    // armored files are streamed like binary ones, so they take the same constant memory
     let reader: Box<dyn futures_io::AsyncBufRead + Unpin + Send + Sync> = if armor {
-        file.read_to_end(&mut contents)
+        let path = file_path.clone();
+        let file = tauri::async_runtime::spawn_blocking(move || open_armored(&path))
             .await
-            .map_err(|e| e.to_string())?;
-        Box::new(age::armor::ArmoredReader::from_async_reader(&contents[..]))
+            .map_err(|e| e.to_string())??;
+        Box::new(age::armor::ArmoredReader::from_async_reader(
+            BufReader::new(File::from_std(file)).compat(),
+        ))
     } else {
+        let file = File::open(file_path).await.map_err(|e| e.to_string())?;
         Box::new(BufReader::new(file).compat())
     };
 
@@ -224,32 +346,14 @@ where
             .await
             .map_err(|e| e.to_string())?;
 
-    let decrypted_output = file_path.with_extension("");
-    let output = File::create(&decrypted_output)
+    let mut decrypted_reader = decrypt_async(decryptor, identity.clone())
         .await
-        .map_err(|e| e.to_string())?;
-    let mut file_writer = BufWriter::new(output);
+        .map_err(|e| format!("decryption failed: {e}"))?;
 
-    let mut decrypted_reader = {
-        let result = match identity {
-            WildcardIdentity::Hybrid(hybrid_identity) => decryptor.decrypt_async(
-                vec![
-                    identity as _,
-                    &WildcardIdentity::X25519(hybrid_identity.to_x25519()) as _,
-                ]
-                .into_iter(),
-            ),
-            _ => decryptor.decrypt_async(std::iter::once(identity as _)),
-        };
-        if let Ok(decryptor_reader) = result {
-            decryptor_reader
-        } else {
-            return Err(format!(
-                "decryption failed: {}",
-                result.err().unwrap().to_string()
-            ));
-        }
-    };
+    // the plaintext isn't authenticated until the last chunk has been read, so it only gets its real
+    // name after that. until then it's removed again on any error
+    let (pending_output, output) = output::PendingOutput::create(output_path, overwrite).await?;
+    let mut file_writer = BufWriter::new(output);
 
     let target_size = MEGABYTE * 4; // only send at most every 4MB
     let mut accumulator: usize = 0;
@@ -273,6 +377,8 @@ where
             accumulator = 0;
         }
     }
+    file_writer.shutdown().await.map_err(|e| e.to_string())?;
+    drop(file_writer);
     callback(accumulator); // ensure that it's sent at some point
-    Ok(decrypted_output)
+    pending_output.persist().await
 }
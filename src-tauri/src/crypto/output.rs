@@ -0,0 +1,478 @@
+// where encrypted and decrypted files are written. by default each output sits next to its input,
+// named `<name>.age` when encrypting and `<name>` minus `.age` when decrypting. a batch can instead
+// be sent to another directory and named from a template, e.g. `{stem}-backup.age`.
+// every output path is worked out before anything is written, so a bad template, two files that
+// would land on the same name or an output that's already there stop the batch before it starts.
+// nothing is ever written over one of the inputs, whatever the conflict policy says, however the
+// output's path is spelled.
+use serde::Deserialize;
+use std::collections::HashSet;
+use std::path::{is_separator, Path, PathBuf};
+use tokio::fs::{File, OpenOptions};
+
+pub const ENCRYPT_TEMPLATE: &str = "{name}.age";
+pub const DECRYPT_TEMPLATE: &str = "{stem}";
+/// for decrypting files that don't end in `.age`, where `{stem}` would just drop their real extension
+pub const DECRYPT_UNKNOWN_TEMPLATE: &str = "{name}.decrypted";
+pub const ARCHIVE_TEMPLATE: &str = "{name}.tar.age";
+pub const COMPRESSED_ARCHIVE_TEMPLATE: &str = "{name}.tar.zst.age";
+/// how many numbered names to try before giving up on `ConflictPolicy::Rename`
+const MAX_RENAMES: usize = 1000;
+
+#[derive(Deserialize, Debug, Clone, Copy, specta::Type)]
+pub enum OutputKind {
+    Encrypted,
+    Decrypted,
+    /// folders packed into one encrypted tar archive
+    Archive,
+    /// the same, compressed with zstd
+    CompressedArchive,
+}
+
+impl OutputKind {
+    fn default_template(self, input: &Path) -> &'static str {
+        match self {
+            Self::Encrypted => ENCRYPT_TEMPLATE,
+            Self::Decrypted if input.extension().is_some_and(|ext| ext == "age") => {
+                DECRYPT_TEMPLATE
+            }
+            Self::Decrypted => DECRYPT_UNKNOWN_TEMPLATE,
+            Self::Archive => ARCHIVE_TEMPLATE,
+            Self::CompressedArchive => COMPRESSED_ARCHIVE_TEMPLATE,
+        }
+    }
+}
+
+/// what to do when an output file already exists
+#[derive(Deserialize, Debug, Clone, Copy, Default, PartialEq, specta::Type)]
+pub enum ConflictPolicy {
+    /// stop before anything is written
+    #[default]
+    Fail,
+    /// write to `name (1).ext`, `name (2).ext`, ... instead
+    Rename,
+    /// replace it. the frontend only sends this once the user has confirmed
+    Overwrite,
+}
+
+#[derive(Deserialize, Debug, Clone, Default, specta::Type)]
+pub struct OutputOptions {
+    /// write every output here instead of next to its input
+    pub directory: Option<String>,
+    /// the name of each output. `{name}` is the input's file name, `{stem}` is that without its last
+    /// extension, `{ext}` is the last extension without the dot, and `{index}` is the file's
+    /// position in the batch, counting from 1
+    pub name_template: Option<String>,
+    /// defaults to `Fail`
+    pub on_conflict: Option<ConflictPolicy>,
+}
+
+/// fill in a name template for one input
+fn render(template: &str, input: &Path, index: usize) -> Result<String, String> {
+    let file_name = |part: Option<&std::ffi::OsStr>| {
+        part.map(|part| part.to_string_lossy().to_string())
+            .unwrap_or_default()
+    };
+    let mut name = String::new();
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        name.push_str(&rest[..start]);
+        let end = rest[start..]
+            .find('}')
+            .ok_or(format!("unclosed `{{` in the name template \"{template}\""))?;
+        let placeholder = &rest[start + 1..start + end];
+        match placeholder {
+            "name" => name.push_str(&file_name(input.file_name())),
+            "stem" => name.push_str(&file_name(input.file_stem())),
+            "ext" => name.push_str(&file_name(input.extension())),
+            "index" => name.push_str(&index.to_string()),
+            _ => {
+                return Err(format!(
+                    "unknown placeholder `{{{placeholder}}}` in the name template. use {{name}}, {{stem}}, {{ext}} or {{index}}"
+                ))
+            }
+        }
+        rest = &rest[start + end + 1..];
+    }
+    name.push_str(rest);
+    Ok(name)
+}
+
+/// `path` with ` (n)` added before its extension
+fn numbered(path: &Path, n: usize) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let name = match path.extension() {
+        Some(ext) => format!("{stem} ({n}).{}", ext.to_string_lossy()),
+        None => format!("{stem} ({n})"),
+    };
+    path.with_file_name(name)
+}
+
+/// `path` with its folder canonicalized, so two spellings of the same location compare equal, e.g.
+/// through a link to the folder. the file name is kept as it is, since replacing a link replaces
+/// the link rather than the file it points to
+fn resolved(path: &Path) -> PathBuf {
+    let parent = path
+        .parent()
+        .filter(|parent| !parent.as_os_str().is_empty())
+        .unwrap_or(Path::new("."));
+    match (parent.canonicalize(), path.file_name()) {
+        (Ok(parent), Some(name)) => parent.join(name),
+        _ => path.to_path_buf(),
+    }
+}
+
+/// whether `a` and `b` are the same file on disk, which catches what `resolved` can't, like names
+/// that only differ in case on a case-insensitive filesystem
+#[cfg(unix)]
+fn same_file(a: &Path, b: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    match (std::fs::metadata(a), std::fs::metadata(b)) {
+        (Ok(a), Ok(b)) => a.dev() == b.dev() && a.ino() == b.ino(),
+        _ => false,
+    }
+}
+
+#[cfg(not(unix))]
+fn same_file(a: &Path, b: &Path) -> bool {
+    matches!((a.canonicalize(), b.canonicalize()), (Ok(a), Ok(b)) if a == b)
+}
+
+/// the output path of every input, in order, with the conflict policy applied
+pub fn output_paths(
+    inputs: &[PathBuf],
+    options: &OutputOptions,
+    kind: OutputKind,
+) -> Result<Vec<PathBuf>, String> {
+    let policy = options.on_conflict.unwrap_or_default();
+    let template = options
+        .name_template
+        .as_deref()
+        .filter(|template| !template.trim().is_empty());
+    let directory = match options.directory.as_deref() {
+        Some(directory) if !directory.is_empty() => {
+            let directory = PathBuf::from(directory);
+            if !directory.is_dir() {
+                return Err(format!(
+                    "the output directory {} does not exist",
+                    directory.display()
+                ));
+            }
+            Some(directory)
+        }
+        _ => None,
+    };
+
+    let mut outputs = Vec::with_capacity(inputs.len());
+    let resolved_inputs: HashSet<PathBuf> = inputs.iter().map(|input| resolved(input)).collect();
+    let mut seen = resolved_inputs.clone();
+    for (index, input) in inputs.iter().enumerate() {
+        let template = template.unwrap_or_else(|| kind.default_template(input));
+        let name = render(template, input, index + 1)?;
+        if name.is_empty() || name == "." || name == ".." || name.contains(is_separator) {
+            return Err(format!(
+                "\"{name}\" is not a valid file name. the name template can't contain folders"
+            ));
+        }
+        let mut output = match &directory {
+            Some(directory) => directory.join(&name),
+            None => input.with_file_name(&name),
+        };
+        let overwrites_input = resolved_inputs.contains(&resolved(&output))
+            || (output.exists() && inputs.iter().any(|input| same_file(input, &output)));
+        if overwrites_input {
+            return Err(format!(
+                "{} would be written over one of the files being {}. choose another output directory or name",
+                output.display(),
+                match kind {
+                    OutputKind::Encrypted => "encrypted",
+                    OutputKind::Decrypted => "decrypted",
+                    OutputKind::Archive | OutputKind::CompressedArchive => "archived",
+                }
+            ));
+        }
+        if seen.contains(&resolved(&output)) && policy != ConflictPolicy::Rename {
+            return Err(format!(
+                "more than one file would be written to {}. add {{index}} to the name template to tell them apart",
+                output.display()
+            ));
+        }
+        if output.exists() || seen.contains(&resolved(&output)) {
+            match policy {
+                ConflictPolicy::Fail => {
+                    return Err(format!("{} already exists", output.display()));
+                }
+                ConflictPolicy::Rename => {
+                    output = (1..=MAX_RENAMES)
+                        .map(|n| numbered(&output, n))
+                        .find(|candidate| {
+                            !candidate.exists() && !seen.contains(&resolved(candidate))
+                        })
+                        .ok_or(format!(
+                            "couldn't find a free name for {}",
+                            output.display()
+                        ))?;
+                }
+                ConflictPolicy::Overwrite => {}
+            }
+        }
+        seen.insert(resolved(&output));
+        outputs.push(output);
+    }
+    Ok(outputs)
+}
+
+/// outputs that already exist, so the user can be asked before they're overwritten
+pub fn conflicts(
+    inputs: &[PathBuf],
+    options: &OutputOptions,
+    kind: OutputKind,
+) -> Result<Vec<PathBuf>, String> {
+    let options = OutputOptions {
+        on_conflict: Some(ConflictPolicy::Overwrite),
+        ..options.clone()
+    };
+    Ok(output_paths(inputs, &options, kind)?
+        .into_iter()
+        .filter(|output| output.exists())
+        .collect())
+}
+
+/// an output being written. everything goes to a hidden temporary file next to the real output,
+/// which only takes the real name in `persist`, once the whole file has been written (and, when
+/// decrypting, authenticated). dropping it without persisting deletes the temporary file, so a
+/// failure never leaves half a file behind
+pub struct PendingOutput {
+    temp: PathBuf,
+    path: PathBuf,
+    overwrite: bool,
+    persisted: bool,
+}
+
+impl PendingOutput {
+    /// start writing `path`. unless `overwrite` is set, `persist` fails if something appeared at
+    /// `path` after the conflict check, rather than replacing it
+    pub async fn create(path: &Path, overwrite: bool) -> Result<(PendingOutput, File), String> {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let temp = path.with_file_name(format!(".{name}.{:08x}.tmp", rand::random::<u32>()));
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp)
+            .await
+            .map_err(|e| format!("couldn't create {}: {e}", path.display()))?;
+        let output = PendingOutput {
+            temp,
+            path: path.to_path_buf(),
+            overwrite,
+            persisted: false,
+        };
+        Ok((output, file))
+    }
+
+    /// move the finished file into place. the writer must have been flushed and dropped first
+    pub async fn persist(mut self) -> Result<(), String> {
+        let failed = |e: std::io::Error| format!("couldn't write {}: {e}", self.path.display());
+        OpenOptions::new()
+            .write(true)
+            .open(&self.temp)
+            .await
+            .map_err(failed)?
+            .sync_all()
+            .await
+            .map_err(failed)?;
+        if self.overwrite {
+            tokio::fs::rename(&self.temp, &self.path)
+                .await
+                .map_err(failed)?;
+        } else {
+            // a hard link can't replace an existing file, unlike a rename
+            match tokio::fs::hard_link(&self.temp, &self.path).await {
+                Ok(()) => {
+                    let _ = tokio::fs::remove_file(&self.temp).await;
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => {
+                    return Err(format!("{} already exists", self.path.display()));
+                }
+                // some filesystems, like FAT, have no hard links
+                Err(_) => {
+                    if tokio::fs::try_exists(&self.path).await.unwrap_or(true) {
+                        return Err(format!("{} already exists", self.path.display()));
+                    }
+                    tokio::fs::rename(&self.temp, &self.path)
+                        .await
+                        .map_err(failed)?;
+                }
+            }
+        }
+        self.persisted = true;
+        Ok(())
+    }
+}
+
+impl Drop for PendingOutput {
+    fn drop(&mut self) {
+        if !self.persisted {
+            let _ = std::fs::remove_file(&self.temp);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn options(directory: &Path, on_conflict: ConflictPolicy) -> OutputOptions {
+        OutputOptions {
+            directory: Some(directory.to_string_lossy().to_string()),
+            name_template: Some("{name}".to_string()),
+            on_conflict: Some(on_conflict),
+        }
+    }
+
+    /// empty files called `names` in `dir`
+    fn files(dir: &Path, names: &[&str]) -> Vec<PathBuf> {
+        names
+            .iter()
+            .map(|name| {
+                let path = dir.join(name);
+                std::fs::write(&path, "").unwrap();
+                path
+            })
+            .collect()
+    }
+
+    #[test]
+    fn defaults_to_next_to_the_input() {
+        let dir = tempfile::tempdir().unwrap();
+        let inputs = files(dir.path(), &["notes.txt", "photo.jpg.age", "plain.txt"]);
+        let defaults = OutputOptions::default();
+
+        let encrypted = output_paths(&inputs[..1], &defaults, OutputKind::Encrypted).unwrap();
+        assert_eq!(encrypted, [dir.path().join("notes.txt.age")]);
+        let decrypted = output_paths(&inputs[1..], &defaults, OutputKind::Decrypted).unwrap();
+        assert_eq!(
+            decrypted,
+            [
+                dir.path().join("photo.jpg"),
+                dir.path().join("plain.txt.decrypted")
+            ]
+        );
+        let archive = output_paths(&inputs[..1], &defaults, OutputKind::CompressedArchive).unwrap();
+        assert_eq!(archive, [dir.path().join("notes.txt.tar.zst.age")]);
+    }
+
+    #[test]
+    fn renders_the_template_into_the_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = tempfile::tempdir().unwrap();
+        let inputs = files(dir.path(), &["a.txt", "b.md"]);
+        let options = OutputOptions {
+            name_template: Some("{index}-{stem}.{ext}.age".to_string()),
+            ..options(out.path(), ConflictPolicy::Fail)
+        };
+        let outputs = output_paths(&inputs, &options, OutputKind::Encrypted).unwrap();
+        assert_eq!(
+            outputs,
+            [
+                out.path().join("1-a.txt.age"),
+                out.path().join("2-b.md.age")
+            ]
+        );
+    }
+
+    #[test]
+    fn refuses_bad_templates() {
+        let dir = tempfile::tempdir().unwrap();
+        let inputs = files(dir.path(), &["a.txt"]);
+        for (template, error) in [
+            ("{size}.age", "unknown placeholder `{size}`"),
+            ("{name.age", "unclosed `{`"),
+            ("sub/{name}", "is not a valid file name"),
+            ("..", "is not a valid file name"),
+        ] {
+            let options = OutputOptions {
+                name_template: Some(template.to_string()),
+                ..OutputOptions::default()
+            };
+            let result = output_paths(&inputs, &options, OutputKind::Encrypted);
+            assert!(result.unwrap_err().contains(error), "{template}");
+        }
+    }
+
+    #[test]
+    fn refuses_two_inputs_with_the_same_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = tempfile::tempdir().unwrap();
+        let inputs = files(dir.path(), &["a.txt", "a.md"]);
+        let options = OutputOptions {
+            name_template: Some("{stem}.age".to_string()),
+            ..options(out.path(), ConflictPolicy::Fail)
+        };
+        let error = output_paths(&inputs, &options, OutputKind::Encrypted).unwrap_err();
+        assert!(error.contains("more than one file would be written"));
+    }
+
+    #[test]
+    fn numbers_conflicts_when_renaming() {
+        let dir = tempfile::tempdir().unwrap();
+        let out = tempfile::tempdir().unwrap();
+        let inputs = files(dir.path(), &["a.txt", "a.md"]);
+        files(out.path(), &["a.age", "a (1).age"]);
+        let options = OutputOptions {
+            name_template: Some("{stem}.age".to_string()),
+            ..options(out.path(), ConflictPolicy::Rename)
+        };
+        let outputs = output_paths(&inputs, &options, OutputKind::Encrypted).unwrap();
+        assert_eq!(
+            outputs,
+            [out.path().join("a (2).age"), out.path().join("a (3).age")]
+        );
+    }
+
+    #[test]
+    fn fails_on_an_existing_output() {
+        let dir = tempfile::tempdir().unwrap();
+        let inputs = files(dir.path(), &["a.txt", "a.txt.age"]);
+        let options = OutputOptions {
+            on_conflict: Some(ConflictPolicy::Fail),
+            ..OutputOptions::default()
+        };
+        let error = output_paths(&inputs[..1], &options, OutputKind::Encrypted).unwrap_err();
+        assert!(error.ends_with("a.txt.age already exists"));
+
+        let overwrite = OutputOptions {
+            on_conflict: Some(ConflictPolicy::Overwrite),
+            ..OutputOptions::default()
+        };
+        let outputs = output_paths(&inputs[..1], &overwrite, OutputKind::Encrypted).unwrap();
+        assert_eq!(outputs, [inputs[1].clone()]);
+        assert_eq!(
+            conflicts(&inputs[..1], &options, OutputKind::Encrypted).unwrap(),
+            [inputs[1].clone()]
+        );
+    }
+
+    #[test]
+    fn refuses_a_missing_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let inputs = files(dir.path(), &["a.txt"]);
+        let options = options(&dir.path().join("missing"), ConflictPolicy::Fail);
+        let error = output_paths(&inputs, &options, OutputKind::Encrypted).unwrap_err();
+        assert!(error.contains("does not exist"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn refuses_an_input_through_a_linked_directory() {
+        let dir = tempfile::tempdir().unwrap();
+        let input = dir.path().join("notes.txt");
+        std::fs::write(&input, "keep me").unwrap();
+        let link = dir.path().join("link");
+        std::os::unix::fs::symlink(dir.path(), &link).unwrap();
+
+        let options = options(&link, ConflictPolicy::Overwrite);
+        let error = output_paths(&[input], &options, OutputKind::Encrypted).unwrap_err();
+        assert!(error.contains("would be written over one of the files"));
+    }
+}
@@ -0,0 +1,224 @@
+// age plugins, e.g. `age-plugin-yubikey`. age runs the `age-plugin-<name>` binary from PATH and
+// talks to it over the plugin protocol. anything the plugin wants to tell or ask the user is sent to
+// the frontend as a `plugin-prompt` event, and the frontend replies with `answer_plugin_prompt`.
+// passphrases for protected ssh keys are asked for the same way.
+use age::plugin::{IdentityPluginV1, RecipientPluginV1};
+use parking_lot::Mutex;
+use secrecy::SecretString;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{mpsc, LazyLock, OnceLock};
+use std::time::Duration;
+use tauri::Emitter;
+
+/// plugins often wait on hardware, like a key being touched, so be patient
+const ANSWER_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// where prompts are sent. `true` if the prompt went through
+static PROMPT_SINK: OnceLock<Box<dyn Fn(PluginPrompt) -> bool + Send + Sync>> = OnceLock::new();
+static PENDING: LazyLock<Mutex<HashMap<u32, mpsc::Sender<PluginAnswer>>>> =
+    LazyLock::new(|| Mutex::new(HashMap::new()));
+static NEXT_ID: AtomicU32 = AtomicU32::new(0);
+
+/// `source` is who's asking, e.g. `age-plugin-yubikey`, or `ssh key` for an ssh key's passphrase
+#[derive(Serialize, Debug, Clone, specta::Type, tauri_specta::Event)]
+#[serde(tag = "kind", rename_all = "camelCase")]
+pub enum PluginPrompt {
+    /// just information, e.g. "touch your yubikey". needs no answer
+    Message { source: String, message: String },
+    /// answer with `confirm`. without a `no` button the only choice is `yes`
+    Confirm {
+        id: u32,
+        source: String,
+        message: String,
+        yes: String,
+        no: Option<String>,
+    },
+    /// answer with `text`. `secret` is set for PINs and passphrases
+    Text {
+        id: u32,
+        source: String,
+        description: String,
+        secret: bool,
+    },
+}
+
+#[derive(Deserialize, Debug, Clone, specta::Type)]
+#[serde(tag = "kind", content = "value", rename_all = "camelCase")]
+pub enum PluginAnswer {
+    Confirm(bool),
+    Text(String),
+    Cancel,
+}
+
+/// needed before any plugin can prompt the user. without it, prompts are refused
+pub fn set_app_handle(app_handle: tauri::AppHandle) {
+    let _ = PROMPT_SINK.set(Box::new(move |prompt| {
+        app_handle.emit("plugin-prompt", prompt).is_ok()
+    }));
+}
+
+/// hand an answer from the frontend to the plugin waiting on it
+pub fn answer(id: u32, answer: PluginAnswer) -> Result<(), String> {
+    let gone = || "the plugin is no longer waiting for an answer".to_string();
+    let sender = PENDING.lock().remove(&id).ok_or_else(gone)?;
+    sender.send(answer).map_err(|_| gone())
+}
+
+pub fn recipient_plugin(
+    recipient: &age::plugin::Recipient,
+) -> Result<RecipientPluginV1<UiCallbacks>, age::EncryptError> {
+    RecipientPluginV1::new(
+        recipient.plugin(),
+        std::slice::from_ref(recipient),
+        &[],
+        UiCallbacks::new(&format!("age-plugin-{}", recipient.plugin())),
+    )
+}
+
+pub fn identity_plugin(
+    identity: &age::plugin::Identity,
+) -> Result<IdentityPluginV1<UiCallbacks>, age::DecryptError> {
+    IdentityPluginV1::new(
+        identity.plugin(),
+        std::slice::from_ref(identity),
+        UiCallbacks::new(&format!("age-plugin-{}", identity.plugin())),
+    )
+}
+
+/// routes a plugin's prompts to the frontend
+#[derive(Clone)]
+pub struct UiCallbacks {
+    source: String,
+}
+
+impl UiCallbacks {
+    pub(crate) fn new(source: &str) -> UiCallbacks {
+        UiCallbacks {
+            source: source.to_string(),
+        }
+    }
+
+    fn emit(&self, prompt: PluginPrompt) -> bool {
+        PROMPT_SINK.get().is_some_and(|sink| sink(prompt))
+    }
+
+    /// send a prompt and block until it's answered. `None` if it couldn't be sent or the user took
+    /// too long
+    fn ask(&self, prompt: impl FnOnce(u32) -> PluginPrompt) -> Option<PluginAnswer> {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let (sender, receiver) = mpsc::channel();
+        PENDING.lock().insert(id, sender);
+        let answer = if self.emit(prompt(id)) {
+            receiver.recv_timeout(ANSWER_TIMEOUT).ok()
+        } else {
+            None
+        };
+        PENDING.lock().remove(&id);
+        answer
+    }
+
+    fn ask_text(&self, description: &str, secret: bool) -> Option<String> {
+        match self.ask(|id| PluginPrompt::Text {
+            id,
+            source: self.source.clone(),
+            description: description.to_string(),
+            secret,
+        })? {
+            PluginAnswer::Text(text) => Some(text),
+            _ => None,
+        }
+    }
+}
+
+impl age::Callbacks for UiCallbacks {
+    fn display_message(&self, message: &str) {
+        self.emit(PluginPrompt::Message {
+            source: self.source.clone(),
+            message: message.to_string(),
+        });
+    }
+
+    fn confirm(&self, message: &str, yes_string: &str, no_string: Option<&str>) -> Option<bool> {
+        match self.ask(|id| PluginPrompt::Confirm {
+            id,
+            source: self.source.clone(),
+            message: message.to_string(),
+            yes: yes_string.to_string(),
+            no: no_string.map(str::to_string),
+        })? {
+            PluginAnswer::Confirm(answer) => Some(answer),
+            _ => None,
+        }
+    }
+
+    fn request_public_string(&self, description: &str) -> Option<String> {
+        self.ask_text(description, false)
+    }
+
+    fn request_passphrase(&self, description: &str) -> Option<SecretString> {
+        self.ask_text(description, true).map(SecretString::from)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::crypto::{self, WildcardRecipient};
+    use std::os::unix::fs::PermissionsExt;
+    use std::sync::Arc;
+
+    /// a stand-in for `age-plugin-stub`. it asks for a PIN, then "wraps" the file key into a stanza
+    /// carrying the PIN it got back, so the test can see the answer made it to the plugin
+    const STUB_PLUGIN: &str = r#"#!/bin/sh
+# skip the recipients and the file key, up to the end of the first phase
+while read -r line && [ "$line" != "-> done" ]; do :; done
+read -r _
+printf -- '-> request-secret\n%s\n' "$(printf 'enter a PIN:' | base64)"
+read -r _
+read -r pin
+printf -- '-> recipient-stanza 0 stub %s\n\n' "$(printf '%s' "$pin" | base64 -d)"
+read -r _
+read -r _
+printf -- '-> done\n\n'
+"#;
+
+    #[test]
+    fn plugin_prompts_are_answered_through_answer_plugin_prompt() {
+        let dir = tempfile::tempdir().unwrap();
+        let binary = dir.path().join("age-plugin-stub");
+        std::fs::write(&binary, STUB_PLUGIN).unwrap();
+        std::fs::set_permissions(&binary, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let path = std::env::var_os("PATH").unwrap_or_default();
+        let paths = std::iter::once(dir.path().to_path_buf()).chain(std::env::split_paths(&path));
+        std::env::set_var("PATH", std::env::join_paths(paths).unwrap());
+
+        // stand in for the frontend: wait for the PIN prompt and answer it like the dialog would
+        let (sender, prompts) = mpsc::channel();
+        let _ = PROMPT_SINK.set(Box::new(move |prompt| sender.send(prompt).is_ok()));
+        let frontend = std::thread::spawn(move || loop {
+            if let PluginPrompt::Text {
+                id,
+                description,
+                secret,
+                ..
+            } = prompts.recv().unwrap()
+            {
+                assert_eq!(description, "enter a PIN:");
+                assert!(secret);
+                crypto::answer_plugin_prompt(id, PluginAnswer::Text("123456".to_string())).unwrap();
+                break;
+            }
+        });
+
+        let recipient =
+            bech32::encode::<bech32::Bech32>(bech32::Hrp::parse("age1stub").unwrap(), &[0; 8])
+                .unwrap();
+        let recipients = Arc::new(vec![WildcardRecipient::from_public(&recipient).unwrap()]);
+        let encryptor = tauri::async_runtime::block_on(crypto::encryptor(recipients)).unwrap();
+        frontend.join().unwrap();
+        let encrypted = encryptor.wrap_output(vec![]).unwrap().finish().unwrap();
+        assert!(encrypted.starts_with(b"age-encryption.org/v1\n-> stub 123456\n"));
+    }
+}
@@ -1,15 +1,17 @@
 // Learn more about Tauri commands at https://tauri.app/develop/calling-rust/
 mod crypto;
+mod session;
 mod store;
 use parking_lot::{Mutex, MutexGuard};
 use specta_typescript::{BigIntExportBehavior, Typescript};
+use std::path::PathBuf;
 use std::sync::Arc;
 use std::thread;
 use std::time::Duration;
 use tauri::Manager;
 use tauri_specta::{collect_commands, collect_events};
 
-use crate::store::Vault;
+use crate::store::{Vault, VaultLoadError, VaultProfiles};
 
 // im ngl idk what im doin
 pub fn set_timeout<F>(delay_ms: u64, f: F)
@@ -22,17 +24,68 @@ where
     });
 }
 
+/// what happened to the vault when the app started. if loading failed, `vault` is `None` until the
+/// user restores a backup, opens another file or starts over.
+#[derive(serde::Serialize, Debug, Clone, specta::Type)]
+#[serde(tag = "state", content = "error", rename_all = "camelCase")]
+pub enum StartupState {
+    FirstOpen,
+    Loaded,
+    LoadFailed(VaultLoadError),
+}
+
 struct AppState {
     vault: Arc<Mutex<Option<store::Vault>>>,
-    first_open: bool,
+    startup: Mutex<StartupState>,
+    session: Mutex<session::Session>,
+    data_dir: PathBuf,
+    profiles: Mutex<VaultProfiles>,
 }
 
 impl AppState {
+    pub fn set_startup_state(&self, startup: StartupState) {
+        *self.startup.lock() = startup;
+    }
+
+    /// where the active vault lives
+    pub fn vault_path(&self) -> PathBuf {
+        self.profiles.lock().active_path()
+    }
+
+    /// load the active vault, replacing whatever vault was open
+    pub fn open_active_vault(&self) -> Result<(), VaultLoadError> {
+        let vault_path = self.vault_path();
+        let (vault, startup) = if !vault_path.exists() {
+            (None, StartupState::FirstOpen)
+        } else {
+            match Vault::load_vault(vault_path.to_str().unwrap()) {
+                Ok(vault) => (Some(vault), StartupState::Loaded),
+                Err(error) => (None, StartupState::LoadFailed(error)),
+            }
+        };
+        *self.vault.lock() = vault;
+        let result = match &startup {
+            StartupState::LoadFailed(error) => Err(error.clone()),
+            _ => Ok(()),
+        };
+        self.set_startup_state(startup);
+        result
+    }
+
+    /// every way of getting at the vault counts as activity, and pushes back the idle auto-lock
     pub fn get_vault(&self) -> MutexGuard<'_, Option<store::Vault>> {
+        self.session.lock().touch();
         self.vault.as_ref().lock()
     }
 
+    /// a handle to the vault for use on another thread
+    pub fn vault_handle(&self) -> Arc<Mutex<Option<store::Vault>>> {
+        self.session.lock().touch();
+        self.vault.clone()
+    }
+
     pub fn with_vault<R>(&self, f: impl FnOnce(&mut store::Vault) -> R) -> Result<R, String> {
+        self.session.lock().touch();
         let mut vault_lock = self.vault.as_ref().lock();
         let vault = vault_lock
             .as_mut()
@@ -42,7 +95,7 @@ impl AppState {
     }
 
     pub async fn save_vault(&self) -> Result<(), String> {
-        let vault_handle = self.vault.clone();
+        let vault_handle = self.vault_handle();
         tauri::async_runtime::spawn_blocking(move || {
             let mut vault = vault_handle.lock();
             vault
@@ -59,7 +112,13 @@ impl AppState {
 #[tauri::command]
 #[specta::specta]
 fn is_first_open(state: tauri::State<AppState>) -> bool {
-    return state.first_open;
+    return matches!(*state.startup.lock(), StartupState::FirstOpen);
+}
+
+#[tauri::command]
+#[specta::specta]
+fn startup_state(state: tauri::State<AppState>) -> StartupState {
+    state.startup.lock().clone()
 }
 
 #[cfg_attr(mobile, tauri::mobile_entry_point)]
@@ -67,32 +126,63 @@ pub fn run() {
     let command_builder = tauri_specta::Builder::<tauri::Wry>::new()
         .commands(collect_commands![
             is_first_open,
+            startup_state,
             store::fetch_keys,
             store::load_vault,
+            store::replace_vault_file,
+            store::reset_vault,
             store::create_vault,
+            store::list_vaults,
+            store::add_vault,
+            store::switch_vault,
+            store::remove_vault,
+            store::change_vault_password,
+            store::vault_kdf_params,
+            store::upgrade_vault_kdf,
+            store::vault_lock_settings,
+            store::set_vault_lock_settings,
+            store::list_vault_backups,
+            store::restore_vault_backup,
             store::vault_exists,
             crypto::generate_keypair,
             crypto::commands::encrypt_file,
             crypto::commands::decrypt_file,
+            crypto::commands::encrypt_directories,
+            crypto::commands::decrypt_archive,
+            crypto::commands::check_output_conflicts,
             crypto::generate_passphrase,
             store::export_key,
+            store::reveal_private_key,
             store::import_key,
+            store::import_recipients,
+            store::export_recipients,
+            store::update_key_metadata,
+            store::search_keys,
+            store::expiring_keys,
+            store::rotate_key,
             store::delete_key,
             store::fetch_key,
+            store::verify_fingerprint,
             store::authenticate,
             store::vault_unlocked,
             store::import_key_text,
             store::check_keyfile_type,
             store::lock_vault,
             store::regenerate_public_identities,
+            store::integrity_report,
+            store::repair_vault,
             crypto::commands::validate_key_file,
             crypto::commands::validate_key_text,
             crypto::commands::armor_check_text,
             crypto::commands::decrypt_text,
             crypto::commands::encrypt_text,
-            crypto::commands::downgrade_hybrid_public_key
+            crypto::commands::downgrade_hybrid_public_key,
+            crypto::commands::answer_plugin_prompt
         ])
-        .events(collect_events![store::VaultStatusUpdate]);
+        .events(collect_events![
+            store::VaultStatusUpdate,
+            crypto::plugin::PluginPrompt
+        ]);
 
     #[cfg(debug_assertions)] // <- Only export on non-release builds
     command_builder
@@ -111,27 +201,28 @@ pub fn run() {
         .invoke_handler(command_builder.invoke_handler())
         .setup(move |app| {
             command_builder.mount_events(app);
+            crypto::plugin::set_app_handle(app.handle().clone());
 
             let app_data_dir = app
                 .path()
                 .app_data_dir()
                 .expect("could not find app data directory");
-            let vault_path = app_data_dir.join("vault.cb");
-            let first_open = !vault_path.exists();
-            if first_open && !app_data_dir.exists() {
-                std::fs::create_dir_all(app_data_dir).expect("failed to create app data directory")
+            if !app_data_dir.exists() {
+                std::fs::create_dir_all(&app_data_dir).expect("failed to create app data directory")
             }
-            app.manage(AppState {
-                vault: Arc::new(Mutex::new(if first_open {
-                    None
-                } else {
-                    Some(
-                        Vault::load_vault(vault_path.to_str().unwrap())
-                            .expect("failed to initialize vault"),
-                    )
-                })),
-                first_open,
-            });
+            // if the vault list is damaged, fall back to the default vault rather than not starting
+            let profiles = VaultProfiles::load(&app_data_dir)
+                .unwrap_or_else(|_| VaultProfiles::initial(&app_data_dir));
+            let state = AppState {
+                vault: Arc::new(Mutex::new(None)),
+                startup: Mutex::new(StartupState::FirstOpen),
+                session: Mutex::new(session::Session::new()),
+                data_dir: app_data_dir,
+                profiles: Mutex::new(profiles),
+            };
+            // a vault that fails to load is reported through `startup_state`
+            let _ = state.open_active_vault();
+            app.manage(state);
             Ok(())
         })
         .run(tauri::generate_context!())
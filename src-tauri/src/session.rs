@@ -0,0 +1,140 @@
+// auto-lock for the vault key.
+// while the vault is unlocked there is one timer, which sleeps until the earliest deadline (idle or
+// maximum session length). when it wakes up it checks again, since activity may have pushed the idle
+// deadline back, and either goes back to sleep or locks the vault.
+use std::time::{Duration, Instant};
+use tauri::{AppHandle, Emitter, Manager};
+
+use crate::store::{LockSettings, VaultStatusUpdate};
+use crate::{set_timeout, AppState};
+
+/// how long after the password is entered again a private key can be revealed or exported
+const REAUTH_WINDOW: Duration = Duration::from_secs(60);
+
+pub struct Session {
+    settings: LockSettings,
+    /// bumped every time a session starts or ends, so timers from an older session do nothing
+    epoch: u64,
+    unlocked_at: Option<Instant>,
+    last_activity: Instant,
+    /// the last time the password was entered. used up by `take_reauthentication`
+    authenticated_at: Option<Instant>,
+}
+
+impl Session {
+    pub fn new() -> Session {
+        Session {
+            settings: LockSettings::default(),
+            epoch: 0,
+            unlocked_at: None,
+            last_activity: Instant::now(),
+            authenticated_at: None,
+        }
+    }
+
+    pub fn touch(&mut self) {
+        self.last_activity = Instant::now();
+    }
+
+    fn end(&mut self) {
+        self.unlocked_at = None;
+        self.authenticated_at = None;
+        self.epoch += 1;
+    }
+
+    /// when the vault should lock, or `None` if it isn't unlocked or neither limit is set
+    fn deadline(&self) -> Option<Instant> {
+        let unlocked_at = self.unlocked_at?;
+        let idle = self
+            .settings
+            .idle_timeout_secs
+            .map(|secs| self.last_activity + Duration::from_secs(secs.into()));
+        let max = self
+            .settings
+            .max_session_secs
+            .map(|secs| unlocked_at + Duration::from_secs(secs.into()));
+        match (idle, max) {
+            (Some(idle), Some(max)) => Some(idle.min(max)),
+            (idle, max) => idle.or(max),
+        }
+    }
+}
+
+/// start the lock timers. call this every time the vault is unlocked
+pub fn start(app_handle: &AppHandle) {
+    let state = app_handle.state::<AppState>();
+    let Ok(settings) = state.with_vault(|vault| vault.lock_settings().clone()) else {
+        return;
+    };
+    let mut session = state.session.lock();
+    session.settings = settings;
+    session.unlocked_at = Some(Instant::now());
+    session.touch();
+    session.epoch += 1;
+    schedule(app_handle.clone(), session.epoch, session.deadline());
+}
+
+/// stop the lock timers. the vault key itself has to be deleted separately
+pub fn end(app_handle: &AppHandle) {
+    let state = app_handle.state::<AppState>();
+    state.session.lock().end();
+}
+
+/// note that the password was just entered, e.g. in the `authenticate` prompt
+pub fn authenticated(app_handle: &AppHandle) {
+    let state = app_handle.state::<AppState>();
+    state.session.lock().authenticated_at = Some(Instant::now());
+}
+
+/// for actions that need the password entered again first, like revealing a private key. succeeds
+/// once per password entry, and only within `REAUTH_WINDOW` of it
+pub fn take_reauthentication(state: &AppState) -> Result<(), String> {
+    match state.session.lock().authenticated_at.take() {
+        Some(at) if at.elapsed() <= REAUTH_WINDOW => Ok(()),
+        _ => Err("enter the vault password again to do this".to_string()),
+    }
+}
+
+/// apply new lock settings to the running session
+pub fn update_settings(app_handle: &AppHandle, settings: LockSettings) {
+    let state = app_handle.state::<AppState>();
+    let mut session = state.session.lock();
+    session.settings = settings;
+    if session.unlocked_at.is_some() {
+        session.epoch += 1;
+        schedule(app_handle.clone(), session.epoch, session.deadline());
+    }
+}
+
+fn schedule(app_handle: AppHandle, epoch: u64, deadline: Option<Instant>) {
+    let Some(deadline) = deadline else {
+        return;
+    };
+    let delay = deadline.saturating_duration_since(Instant::now());
+    set_timeout(delay.as_millis() as u64, move || check(app_handle, epoch));
+}
+
+fn check(app_handle: AppHandle, epoch: u64) {
+    let state = app_handle.state::<AppState>();
+    // the session stays locked until the key is gone. otherwise the vault could be locked and
+    // unlocked again in between, and this timer would delete the key of the new session
+    let mut session = state.session.lock();
+    if session.epoch != epoch {
+        return;
+    }
+    match session.deadline() {
+        Some(deadline) if deadline > Instant::now() => {
+            schedule(app_handle.clone(), epoch, Some(deadline));
+            return;
+        }
+        Some(_) => {}
+        None => return,
+    }
+
+    session.end();
+    if let Some(vault) = state.vault.lock().as_mut() {
+        vault.delete_vault_key();
+    }
+    drop(session);
+    let _ = app_handle.emit("vault-status-update", VaultStatusUpdate::Locked);
+}
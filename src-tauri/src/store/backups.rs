@@ -0,0 +1,104 @@
+// crash-safe writes for the vault file.
+// a save goes to a temp file in the same directory, gets fsynced, and is then renamed over the vault,
+// so the file on disk is always either the old vault or the new one, never half of each.
+// the previous `BACKUP_COUNT` generations are kept as `vault.cb.1` (newest) to `vault.cb.N` (oldest).
+use serde::Serialize;
+use std::fs;
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+use tempfile::NamedTempFile;
+
+pub const BACKUP_COUNT: u32 = 5;
+
+#[derive(Serialize, Debug, Clone, specta::Type)]
+pub struct VaultBackup {
+    pub generation: u32,
+    pub date_modified: SystemTime,
+    pub size: u64,
+}
+
+pub fn backup_path(path: &Path, generation: u32) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".{generation}"));
+    path.with_file_name(name)
+}
+
+/// shift every backup up a generation (dropping the oldest) and copy the current vault into generation 1
+fn rotate(path: &Path) -> std::io::Result<()> {
+    if !path.exists() {
+        return Ok(());
+    }
+    for generation in (1..BACKUP_COUNT).rev() {
+        let from = backup_path(path, generation);
+        if from.exists() {
+            fs::rename(&from, backup_path(path, generation + 1))?;
+        }
+    }
+    fs::copy(path, backup_path(path, 1))?;
+    Ok(())
+}
+
+/// atomically replace the file at `path` with `data`, rotating the old contents into the backups
+pub fn write_atomic(path: &Path, data: &[u8]) -> Result<(), String> {
+    replace(path, data, true)
+}
+
+/// atomically replace the file at `path` with `data`, without touching the backups. for changes
+/// that aren't worth a generation, like the failed unlock counter.
+pub fn overwrite_atomic(path: &Path, data: &[u8]) -> Result<(), String> {
+    replace(path, data, false)
+}
+
+fn replace(path: &Path, data: &[u8], keep_backup: bool) -> Result<(), String> {
+    let dir = path.parent().unwrap_or(Path::new("."));
+
+    // if this fails (e.g. the disk is full) nothing has been touched yet
+    let mut temp =
+        NamedTempFile::new_in(dir).map_err(|e| format!("failed to create temp file: {e}"))?;
+    temp.write_all(data)
+        .and_then(|_| temp.as_file().sync_all())
+        .map_err(|e| format!("failed to write vault file: {e}"))?;
+
+    if keep_backup {
+        rotate(path).map_err(|e| format!("failed to rotate vault backups: {e}"))?;
+    }
+    temp.persist(path)
+        .map_err(|e| format!("failed to replace vault file: {e}"))?;
+
+    // make sure the rename itself is on disk. not every platform lets you open a directory
+    #[cfg(unix)]
+    if let Ok(dir) = fs::File::open(dir) {
+        let _ = dir.sync_all();
+    }
+    Ok(())
+}
+
+/// delete every backup of the vault at `path`. keeps going past a file that can't be removed, so as
+/// many as possible are gone, and then reports it
+pub fn delete_backups(path: &Path) -> Result<(), String> {
+    let mut result = Ok(());
+    for generation in 1..=BACKUP_COUNT {
+        match fs::remove_file(backup_path(path, generation)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                result = Err(format!("failed to delete vault backup {generation}: {e}"));
+            }
+            _ => {}
+        }
+    }
+    result
+}
+
+/// every backup of the vault at `path` that exists, newest first
+pub fn list_backups(path: &Path) -> Vec<VaultBackup> {
+    (1..=BACKUP_COUNT)
+        .filter_map(|generation| {
+            let metadata = fs::metadata(backup_path(path, generation)).ok()?;
+            Some(VaultBackup {
+                generation,
+                date_modified: metadata.modified().ok()?,
+                size: metadata.len(),
+            })
+        })
+        .collect()
+}
@@ -1,13 +1,17 @@
-use crate::crypto::{WildcardIdentity, WildcardRecipient};
-use crate::store::{KeyMetadata, Vault, VaultStatusUpdate};
-use crate::AppState;
-use age::x25519::{Identity, Recipient};
-use age_xwing::{HybridIdentity, HybridRecipient};
+use crate::crypto::ssh;
+use crate::store::{
+    unix_time, ImportReport, IntegrityReport, KdfParams, KeyMetadata, KeyMetadataUpdate, KeySearch,
+    LockSettings, RepairAction, RevealKind, Vault, VaultBackup, VaultLoadError, VaultProfile,
+    VaultProfiles, VaultStatusUpdate,
+};
+use crate::{session, AppState, StartupState};
+use age_xwing::HybridIdentity;
 use secrecy::ExposeSecret;
 use secrecy::SecretString;
 use serde::Deserialize;
-use std::str::FromStr;
+use std::path::Path;
 use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use tauri::{Emitter, Listener, Manager};
 use tokio::fs::File;
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
@@ -15,14 +19,8 @@ use tokio::sync::oneshot;
 
 #[tauri::command]
 #[specta::specta]
-pub fn vault_exists(app_handle: tauri::AppHandle) -> bool {
-    let res = app_handle
-        .path()
-        .app_data_dir()
-        .unwrap()
-        .join("vault.cb")
-        .exists();
-    res
+pub fn vault_exists(state: tauri::State<AppState>) -> bool {
+    state.vault_path().exists()
 }
 
 #[tauri::command]
@@ -35,25 +33,64 @@ pub fn vault_unlocked(state: tauri::State<AppState>) -> bool {
 
 #[tauri::command]
 #[specta::specta]
-pub fn load_vault(
-    state: tauri::State<AppState>,
-    app_handle: tauri::AppHandle,
-) -> Result<(), String> {
-    let vault_location = app_handle.path().app_data_dir().unwrap().join("vault.cb");
+pub fn load_vault(state: tauri::State<AppState>) -> Result<(), VaultLoadError> {
+    let vault_location = state.vault_path();
     let vault_load = Vault::load_vault(vault_location.to_str().unwrap());
     if let Err(error) = vault_load {
+        state.set_startup_state(StartupState::LoadFailed(error.clone()));
         return Err(error);
     }
     let mut vault = state.get_vault();
     *vault = Some(vault_load.unwrap());
+    state.set_startup_state(StartupState::Loaded);
+    Ok(())
+}
+
+/// replace the vault with a copy of the vault file at `path`. the file has to load, and the vault
+/// it replaces is kept as backup 1.
+#[tauri::command]
+#[specta::specta]
+pub async fn replace_vault_file(
+    path: String,
+    state: tauri::State<'_, AppState>,
+    app_handle: tauri::AppHandle,
+) -> Result<(), String> {
+    let vault_path = state.vault_path();
+    let vault =
+        tauri::async_runtime::spawn_blocking(move || Vault::replace_with(&vault_path, &path))
+            .await
+            .map_err(|e| e.to_string())??;
+    *state.get_vault() = Some(vault);
+    state.set_startup_state(StartupState::Loaded);
+    session::end(&app_handle);
+    let _ = app_handle.emit("vault-status-update", VaultStatusUpdate::Locked);
     Ok(())
 }
 
+/// move the current vault file out of the way (it is renamed, never deleted) so a new one can be created
 #[tauri::command]
 #[specta::specta]
-pub async fn create_vault(password: String, app_handle: tauri::AppHandle) -> Result<(), String> {
+pub fn reset_vault(
+    state: tauri::State<AppState>,
+    app_handle: tauri::AppHandle,
+) -> Result<(), String> {
+    let vault_path = state.vault_path();
+    Vault::set_aside(&vault_path)?;
+    *state.get_vault() = None;
+    state.set_startup_state(StartupState::FirstOpen);
+    session::end(&app_handle);
+    let _ = app_handle.emit("vault-status-update", VaultStatusUpdate::Locked);
+    Ok(())
+}
+
+#[tauri::command]
+#[specta::specta]
+pub async fn create_vault(
+    password: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), String> {
     let password = SecretString::from(password);
-    let vault_path = app_handle.path().app_data_dir().unwrap().join("vault.cb");
+    let vault_path = state.vault_path();
 
     let vault_location = vault_path.to_str().unwrap();
     let mut vault = Vault::create_vault(vault_location, &password)?;
@@ -63,6 +100,199 @@ pub async fn create_vault(password: String, app_handle: tauri::AppHandle) -> Res
     Ok(())
 }
 
+#[tauri::command]
+#[specta::specta]
+pub fn list_vaults(state: tauri::State<AppState>) -> VaultProfiles {
+    state.profiles.lock().clone()
+}
+
+/// add a vault to the list. with a `path`, that file is used (and created if it doesn't exist yet).
+/// without one, a new file is made in the app data directory. switch to it to open or create it.
+#[tauri::command]
+#[specta::specta]
+pub fn add_vault(
+    name: String,
+    path: Option<String>,
+    state: tauri::State<AppState>,
+) -> Result<VaultProfile, String> {
+    let mut profiles = state.profiles.lock();
+    let profile = profiles.add(name, path, &state.data_dir)?;
+    if let Err(error) = profiles.save(&state.data_dir) {
+        let _ = profiles.remove(&profile.name);
+        return Err(error);
+    }
+    Ok(profile)
+}
+
+/// lock the open vault and open `name` instead. returns whether it loaded, or still has to be created
+#[tauri::command]
+#[specta::specta]
+pub fn switch_vault(
+    name: String,
+    state: tauri::State<AppState>,
+    app_handle: tauri::AppHandle,
+) -> Result<StartupState, String> {
+    {
+        let mut profiles = state.profiles.lock();
+        profiles.get(&name)?;
+        let previous = std::mem::replace(&mut profiles.active, name);
+        if let Err(error) = profiles.save(&state.data_dir) {
+            profiles.active = previous;
+            return Err(error);
+        }
+    }
+    if let Some(vault) = state.vault.lock().as_mut() {
+        vault.delete_vault_key();
+    }
+    session::end(&app_handle);
+    let _ = state.open_active_vault();
+    let _ = app_handle.emit("vault-status-update", VaultStatusUpdate::Locked);
+    Ok(state.startup.lock().clone())
+}
+
+/// take a vault off the list. with `delete_file`, the vault file and its backups are deleted too
+#[tauri::command]
+#[specta::specta]
+pub fn remove_vault(
+    name: String,
+    delete_file: bool,
+    state: tauri::State<AppState>,
+) -> Result<(), String> {
+    let profile = {
+        let mut profiles = state.profiles.lock();
+        let profile = profiles.remove(&name)?;
+        if let Err(error) = profiles.save(&state.data_dir) {
+            profiles.vaults.push(profile);
+            return Err(error);
+        }
+        profile
+    };
+    if delete_file {
+        Vault::delete_files(Path::new(&profile.path))?;
+    }
+    Ok(())
+}
+
+/// re-encrypt the vault under a new password. every backup, including the copies kept from before
+/// format migrations, is deleted afterwards, so the old password can't be used to restore one
+#[tauri::command]
+#[specta::specta]
+pub async fn change_vault_password(
+    old_password: String,
+    new_password: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), String> {
+    if new_password.len() == 0 {
+        return Err("no password set".to_string());
+    }
+    let old_password = SecretString::from(old_password);
+    let new_password = SecretString::from(new_password);
+    let vault_handle = state.vault_handle();
+    // argon2 is slow on purpose, keep it off the async runtime
+    tauri::async_runtime::spawn_blocking(move || {
+        let mut vault = vault_handle.lock();
+        vault
+            .as_mut()
+            .ok_or("vault not initialized".to_string())?
+            .change_password(old_password, new_password)
+    })
+    .await
+    .map_err(|e| e.to_string())??;
+    Ok(())
+}
+
+#[tauri::command]
+#[specta::specta]
+pub fn list_vault_backups(state: tauri::State<AppState>) -> Vec<VaultBackup> {
+    let vault_path = state.vault_path();
+    Vault::list_backups(&vault_path)
+}
+
+/// replace the vault with one of its backups. the backup's password and HMAC are checked first,
+/// and the restored vault is left unlocked. wrong passwords count towards the vault's lockout.
+#[tauri::command]
+#[specta::specta]
+pub async fn restore_vault_backup(
+    generation: u32,
+    password: String,
+    state: tauri::State<'_, AppState>,
+    app_handle: tauri::AppHandle,
+) -> Result<(), String> {
+    let password = SecretString::from(password);
+    let vault_path = state.vault_path();
+    let vault_handle = state.vault_handle();
+    tauri::async_runtime::spawn_blocking(move || {
+        let mut vault = vault_handle.lock();
+        let restored = Vault::restore_backup(&vault_path, generation, password, vault.as_mut())?;
+        *vault = Some(restored);
+        Ok::<(), String>(())
+    })
+    .await
+    .map_err(|e| e.to_string())??;
+    state.set_startup_state(StartupState::Loaded);
+    session::start(&app_handle);
+    let _ = app_handle.emit("vault-status-update", VaultStatusUpdate::Unlocked);
+    Ok(())
+}
+
+#[tauri::command]
+#[specta::specta]
+pub fn vault_kdf_params(state: tauri::State<AppState>) -> Result<KdfParams, String> {
+    state.with_vault(|vault| vault.kdf_params().clone())
+}
+
+/// re-derive the vault key with stronger argon2 parameters. like `change_vault_password`, this
+/// deletes every backup, since they're still sealed with the old key
+#[tauri::command]
+#[specta::specta]
+pub async fn upgrade_vault_kdf(
+    password: String,
+    params: KdfParams,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), String> {
+    let password = SecretString::from(password);
+    let vault_handle = state.vault_handle();
+    tauri::async_runtime::spawn_blocking(move || {
+        let mut vault = vault_handle.lock();
+        vault
+            .as_mut()
+            .ok_or("vault not initialized".to_string())?
+            .upgrade_kdf(password, params)
+    })
+    .await
+    .map_err(|e| e.to_string())??;
+    Ok(())
+}
+
+#[tauri::command]
+#[specta::specta]
+pub fn vault_lock_settings(state: tauri::State<AppState>) -> Result<LockSettings, String> {
+    state.with_vault(|vault| vault.lock_settings().clone())
+}
+
+/// change when the vault locks itself. takes effect for the current session straight away
+#[tauri::command]
+#[specta::specta]
+pub async fn set_vault_lock_settings(
+    settings: LockSettings,
+    state: tauri::State<'_, AppState>,
+    app_handle: tauri::AppHandle,
+) -> Result<(), String> {
+    let vault_handle = state.vault_handle();
+    let new_settings = settings.clone();
+    tauri::async_runtime::spawn_blocking(move || {
+        let mut vault = vault_handle.lock();
+        vault
+            .as_mut()
+            .ok_or("vault not initialized".to_string())?
+            .set_lock_settings(new_settings)
+    })
+    .await
+    .map_err(|e| e.to_string())??;
+    session::update_settings(&app_handle, settings);
+    Ok(())
+}
+
 #[tauri::command]
 #[specta::specta]
 pub fn fetch_keys(state: tauri::State<AppState>) -> Vec<KeyMetadata> {
@@ -84,15 +314,87 @@ pub fn fetch_keys(state: tauri::State<AppState>) -> Vec<KeyMetadata> {
 #[tauri::command]
 #[specta::specta]
 pub fn fetch_key(name: String, state: tauri::State<AppState>) -> Option<KeyMetadata> {
+    // private keys only leave the vault through `reveal_private_key` and `export_key`
     state
-        .with_vault(|vault| vault.get_key(&name).cloned())
+        .with_vault(|vault| vault.get_key(&name).cloned().map(KeyMetadata::redacted))
         .unwrap_or(None)
 }
 
+/// decrypt a private key to show it. the vault password has to have been entered again (with
+/// `authenticate`) in the last minute, and the reveal is recorded on the key
+#[tauri::command]
+#[specta::specta]
+pub async fn reveal_private_key(
+    id: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<String, String> {
+    session::take_reauthentication(&state)?;
+    let private = state.with_vault(|vault| vault.reveal_private_key(&id, RevealKind::Shown))??;
+    state.save_vault().await?;
+    Ok(private.expose_secret().to_string())
+}
+
+/// check a fingerprint someone read out against the stored key `id`
+#[tauri::command]
+#[specta::specta]
+pub fn verify_fingerprint(
+    id: String,
+    fingerprint: String,
+    state: tauri::State<AppState>,
+) -> Result<bool, String> {
+    state.with_vault(|vault| vault.verify_fingerprint(&id, &fingerprint))?
+}
+
+/// rename a key and replace its email, notes and tags
+#[tauri::command]
+#[specta::specta]
+pub async fn update_key_metadata(
+    id: String,
+    update: KeyMetadataUpdate,
+    state: tauri::State<'_, AppState>,
+) -> Result<KeyMetadata, String> {
+    let key = state.with_vault(|vault| vault.update_key_metadata(&id, update))??;
+    state.save_vault().await?;
+    Ok(key)
+}
+
+#[tauri::command]
+#[specta::specta]
+pub fn search_keys(search: KeySearch, state: tauri::State<AppState>) -> Vec<KeyMetadata> {
+    state
+        .with_vault(|vault| vault.search_keys(&search))
+        .unwrap_or(vec![])
+}
+
+/// replace one of your keys with a newly generated one. the old key is kept for decryption only
+#[tauri::command]
+#[specta::specta]
+pub async fn rotate_key(
+    id: String,
+    expires_at: Option<u64>,
+    state: tauri::State<'_, AppState>,
+) -> Result<KeyMetadata, String> {
+    let successor =
+        state.with_vault(|vault| vault.rotate_key(&id, expires_at.map(unix_time)))??;
+    state.save_vault().await?;
+    Ok(successor)
+}
+
+/// keys that expire within `days` days, or already have, soonest first
+#[tauri::command]
+#[specta::specta]
+pub fn expiring_keys(days: u32, state: tauri::State<AppState>) -> Vec<KeyMetadata> {
+    state
+        .with_vault(|vault| {
+            vault.expiring_keys(Duration::from_secs(u64::from(days) * 24 * 60 * 60))
+        })
+        .unwrap_or(vec![])
+}
+
 #[tauri::command]
 #[specta::specta]
 pub async fn delete_key(id: String, state: tauri::State<'_, AppState>) -> Result<(), String> {
-    state.with_vault(|vault| vault.delete_key(id))?;
+    state.with_vault(|vault| vault.delete_key(id))??;
     state.save_vault().await?;
     Ok(())
 }
@@ -111,23 +413,20 @@ pub async fn export_key(
     mode: KeyExportMode,
     state: tauri::State<'_, AppState>,
 ) -> Result<(), String> {
+    session::take_reauthentication(&state)?;
     let key_content = {
-        let raw_key_content = state.with_vault(|vault| {
-            let key_meta = vault.get_key(&key).expect("key should exist");
-            let key_contents = key_meta.contents.clone();
-            SecretString::from(
-                vault
-                    .decrypt_secret(&key_contents.private.expect("no private key!"))
-                    .unwrap()
-                    .expose_secret()
-                    .to_string(),
-            )
-        })?;
+        let raw_key_content =
+            state.with_vault(|vault| vault.reveal_private_key(&key, RevealKind::Exported))??;
+        // record the export before the key is written anywhere
+        state.save_vault().await?;
 
         let key_is_pq = raw_key_content
             .expose_secret()
             .starts_with("AGE-SECRET-KEY-PQ-");
-        if matches!(mode, KeyExportMode::PostQuantum) {
+        if ssh::is_ssh_secret(raw_key_content.expose_secret()) {
+            // ssh keys go back out as the OpenSSH file they came in as
+            raw_key_content
+        } else if matches!(mode, KeyExportMode::PostQuantum) {
             // if we're supposed to be exporting a postquantum key...
             if key_is_pq {
                 // and the key IS postquantum...
@@ -170,45 +469,51 @@ pub async fn check_keyfile_type(path: String) -> Result<bool, String> {
     if let Err(error) = key_file.read_to_string(&mut key_content).await {
         return Err(error.to_string());
     };
-    return Ok(key_content.starts_with("AGE-SECRET-KEY"));
+    // identity files may start with comments, and may hold public keys too
+    return Ok(key_content.lines().map(str::trim).any(|line| {
+        line.starts_with("AGE-SECRET-KEY")
+            || line.starts_with("AGE-PLUGIN-")
+            || line == ssh::PEM_BEGIN
+    }));
 }
 
-/// command to regenerate the public keys of all identities.
+/// command to regenerate the public keys of all identities. public-only keys are kept.
 #[tauri::command]
 #[specta::specta]
 pub async fn regenerate_public_identities(state: tauri::State<'_, AppState>) -> Result<(), String> {
-    let mut vault = state.get_vault();
-    let vault = vault.as_mut().ok_or("vault not initialized".to_string())?;
-    vault.file.secrets = vault
-        .file
-        .secrets
-        .iter()
-        .filter(|(_, key)| key.contents.private.is_some())
-        .map(|(name, key)| {
-            let key_content = vault
-                .decrypt_secret(&key.contents.private.as_ref().unwrap())
-                .expect("decrypting should not fail");
-            let identity = if key_content
-                .expose_secret()
-                .starts_with("AGE-SECRET-KEY-PQ-")
-            {
-                WildcardIdentity::Hybrid(
-                    HybridIdentity::from_string(key_content)
-                        .expect("making an object from this key should not fail"),
-                )
-            } else {
-                WildcardIdentity::X25519(
-                    age::x25519::Identity::from_str(key_content.expose_secret())
-                        .expect("making an object from this key should not fail"),
-                )
-            };
-            let mut key = key.clone();
-            key.contents = vault
-                .keypair_from(identity)
-                .expect("keypair generation should not fail");
-            (name.clone(), key)
-        })
-        .collect();
+    state.with_vault(|vault| vault.regenerate_public_identities())??;
+    state.save_vault().await?;
+    Ok(())
+}
+
+/// what changed in the vault, if it failed its integrity check on unlock
+#[tauri::command]
+#[specta::specta]
+pub fn integrity_report(state: tauri::State<AppState>) -> Option<IntegrityReport> {
+    state
+        .with_vault(|vault| vault.integrity_problem().cloned())
+        .unwrap_or(None)
+}
+
+/// make a vault that failed its integrity check writable again
+#[tauri::command]
+#[specta::specta]
+pub async fn repair_vault(
+    action: RepairAction,
+    state: tauri::State<'_, AppState>,
+    app_handle: tauri::AppHandle,
+) -> Result<(), String> {
+    let vault_handle = state.vault_handle();
+    tauri::async_runtime::spawn_blocking(move || {
+        let mut vault = vault_handle.lock();
+        vault
+            .as_mut()
+            .ok_or("vault not initialized".to_string())?
+            .repair(action)
+    })
+    .await
+    .map_err(|e| e.to_string())??;
+    let _ = app_handle.emit("vault-status-update", VaultStatusUpdate::Unlocked);
     Ok(())
 }
 
@@ -217,37 +522,16 @@ pub async fn regenerate_public_identities(state: tauri::State<'_, AppState>) ->
 pub async fn import_key_text(
     name: String,
     key_content: String,
+    expires_at: Option<u64>,
     state: tauri::State<'_, AppState>,
-) -> Result<String, String> {
-    let is_private = key_content.starts_with("AGE-SECRET-KEY");
-    let key = if is_private {
-        let identity = if key_content.starts_with("AGE-SECRET-KEY-PQ-") {
-            WildcardIdentity::Hybrid(HybridIdentity::from_string(SecretString::from(
-                key_content,
-            ))?)
-        } else {
-            WildcardIdentity::X25519(
-                Identity::from_str(key_content.clone().as_str()).map_err(|e| e.to_string())?,
-            )
-        };
-        state.with_vault(|vault| {
-            vault.new_key(
-                name,
-                identity.to_public().unwrap().to_string().unwrap(),
-                Some(identity.to_string().unwrap()),
-            )
-        })?
-    } else {
-        let recipient = if key_content.starts_with("age1pq1") {
-            WildcardRecipient::Hybrid(HybridRecipient::from_string(&key_content)?)
-        } else {
-            WildcardRecipient::X25519(Recipient::from_str(&key_content)?)
-        };
-        state.with_vault(|vault| vault.new_key(name, recipient.to_string()?, None))?
-    }?;
-    state.with_vault(|vault| vault.put_key(key))??;
-    state.save_vault().await?;
-    Ok("key import complete".to_string())
+) -> Result<ImportReport, String> {
+    let report = state.with_vault(|vault| {
+        vault.import_keys(&name, &key_content, expires_at.map(unix_time), false)
+    })??;
+    if report.imported > 0 {
+        state.save_vault().await?;
+    }
+    Ok(report)
 }
 
 #[tauri::command]
@@ -255,17 +539,23 @@ pub async fn import_key_text(
 pub async fn import_key(
     name: String,
     path: String,
+    expires_at: Option<u64>,
     state: tauri::State<'_, AppState>,
-) -> Result<String, String> {
+) -> Result<ImportReport, String> {
     if name.len() == 0 {
         return Err("no name set".to_string());
     }
+    let key_content = read_key_file(&path).await?;
+    return import_key_text(name, key_content, expires_at, state).await;
+}
+
+async fn read_key_file(path: &str) -> Result<String, String> {
     let mut key_file = File::open(path).await.map_err(|e| e.to_string())?;
     let mut key_content = String::default();
 
-    // theres genuinely no case in which a key file should be greater than 10 kb
-    // this is extremely generous
-    if key_file.metadata().await.map_err(|e| e.to_string())?.len() > (1024 * 10) {
+    // identity files can hold many keys, and post-quantum public keys are ~2 kb each,
+    // but a megabyte is still far more than any real key file
+    if key_file.metadata().await.map_err(|e| e.to_string())?.len() > (1024 * 1024) {
         return Err("key file too large".to_string());
     };
 
@@ -273,8 +563,46 @@ pub async fn import_key(
         .read_to_string(&mut key_content)
         .await
         .map_err(|e| e.to_string())?;
+    Ok(key_content)
+}
+
+/// import every public key in an age recipients file. secret keys in it are reported as invalid
+#[tauri::command]
+#[specta::specta]
+pub async fn import_recipients(
+    name: String,
+    path: String,
+    expires_at: Option<u64>,
+    state: tauri::State<'_, AppState>,
+) -> Result<ImportReport, String> {
+    if name.trim().is_empty() {
+        return Err("no name set".to_string());
+    }
+    let key_content = read_key_file(&path).await?;
+    let report = state.with_vault(|vault| {
+        vault.import_keys(&name, &key_content, expires_at.map(unix_time), true)
+    })??;
+    if report.imported > 0 {
+        state.save_vault().await?;
+    }
+    Ok(report)
+}
 
-    return import_key_text(name, key_content, state).await;
+/// write the public keys of `ids` (or of every key that can be encrypted to) as an age recipients
+/// file, with each key's name in a comment
+#[tauri::command]
+#[specta::specta]
+pub async fn export_recipients(
+    ids: Option<Vec<String>>,
+    path: String,
+    state: tauri::State<'_, AppState>,
+) -> Result<(), String> {
+    let content = state.with_vault(|vault| vault.recipients_file(ids.as_deref()))??;
+    let mut file = File::create(path).await.map_err(|e| e.to_string())?;
+    file.write_all(content.as_bytes())
+        .await
+        .map_err(|e| e.to_string())?;
+    Ok(())
 }
 
 #[tauri::command]
@@ -329,13 +657,25 @@ pub async fn authenticate(
                 break;
             }
             let _ = webview.emit("auth-response", false);
+            if let Some(remaining) = state.with_vault(|vault| vault.lockout_remaining())? {
+                let _ = app_handle.emit(
+                    "vault-status-update",
+                    VaultStatusUpdate::LockedOut {
+                        seconds: remaining.as_secs().max(1),
+                    },
+                );
+            }
         } else {
             break;
         };
     }
-    if integrity_check_fail {
-        let _ = regenerate_public_identities(state).await;
+    // if the integrity check failed, the vault stays read-only until the user picks a repair action
+    if !integrity_check_fail && state.with_vault(|vault| vault.needs_mac_upgrade())? {
+        // authenticate the whole file now that the old secrets-only HMAC has been checked
+        state.save_vault().await?;
     }
+    session::start(&app_handle);
+    session::authenticated(&app_handle);
     let result = if integrity_check_fail {
         VaultStatusUpdate::VerificationFail
     } else {
@@ -355,6 +695,7 @@ pub async fn lock_vault(
     state.with_vault(|vault| {
         vault.delete_vault_key();
     })?;
+    session::end(&app_handle);
     let _ = app_handle.emit("vault-status-update", VaultStatusUpdate::Locked);
     Ok(())
 }
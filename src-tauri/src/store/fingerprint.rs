@@ -0,0 +1,48 @@
+// short fingerprints of public keys, so two people can check they have the same key by reading it
+// out over the phone. post-quantum public keys are ~2000 characters, far too long to compare by eye.
+// a fingerprint is the first 128 bits of the SHA-256 of the public key, as eight groups of four hex
+// digits.
+use sha2::{Digest, Sha256};
+
+use super::Vault;
+use crate::crypto::WildcardRecipient;
+
+const FINGERPRINT_BYTES: usize = 16;
+
+/// e.g. `3f2a 9c01 77de 5b40 e812 0a9f c3d6 4e21`
+pub fn fingerprint(public: &str) -> String {
+    // hash the key as age would write it, so the same key always gives the same fingerprint
+    let canonical = WildcardRecipient::from_public(public)
+        .and_then(|recipient| recipient.to_string())
+        .unwrap_or_else(|_| public.to_string());
+    let hash = Sha256::digest(canonical.as_bytes());
+    hash[..FINGERPRINT_BYTES]
+        .chunks(2)
+        .map(|group| format!("{:02x}{:02x}", group[0], group[1]))
+        .collect::<Vec<String>>()
+        .join(" ")
+}
+
+/// lowercase hex digits only, so spacing, colons and case don't matter
+fn normalize(fingerprint: &str) -> String {
+    fingerprint
+        .chars()
+        .filter(|c| !c.is_whitespace() && !matches!(c, ':' | '-'))
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+impl Vault {
+    /// whether `fingerprint` (as read out by someone else) is the fingerprint of key `id`
+    pub fn verify_fingerprint(&self, id: &str, fingerprint: &str) -> Result<bool, String> {
+        let key = self.get_key(id).ok_or("key does not exist".to_string())?;
+        let given = normalize(fingerprint);
+        if given.len() != FINGERPRINT_BYTES * 2 || !given.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format!(
+                "a fingerprint is {} hex digits",
+                FINGERPRINT_BYTES * 2
+            ));
+        }
+        Ok(given == normalize(&self::fingerprint(&key.contents.public)))
+    }
+}
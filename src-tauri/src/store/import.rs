@@ -0,0 +1,524 @@
+// importing and exporting keys as text.
+// imports take a single pasted key, an identity file as written by `age-keygen`, or a recipients
+// file as read by `age -R`, with any number of keys and `#` comments. ssh public keys in
+// `authorized_keys` form and OpenSSH private key files work too. comments apply to the key after
+// them: `# name: <name>` names it, `# created: <rfc 3339 date>` sets its creation date and
+// `# public key: age1...` is checked against it. any other comment is ignored. exports are
+// recipients files with a `# name:` comment above each key, so they import back the same.
+use secrecy::SecretString;
+use serde::Serialize;
+use std::time::SystemTime;
+
+use super::{unix_time, KeyMetadata, Vault};
+use crate::crypto::ssh::{self, SshIdentity};
+use crate::crypto::{WildcardIdentity, WildcardRecipient};
+
+/// what happened to one line of the imported text
+#[derive(Serialize, Debug, Clone, specta::Type)]
+#[serde(tag = "status", content = "detail", rename_all = "camelCase")]
+pub enum ImportOutcome {
+    /// contains the id of the new key
+    Imported(String),
+    /// contains the reason, e.g. the key was already in the vault
+    Skipped(String),
+    /// contains the error
+    Invalid(String),
+}
+
+#[derive(Serialize, Debug, Clone, specta::Type)]
+pub struct ImportLine {
+    /// 1-based
+    pub line: u32,
+    pub outcome: ImportOutcome,
+}
+
+/// every non-blank line of the imported text, and what happened to it
+#[derive(Serialize, Debug, Clone, specta::Type)]
+pub struct ImportReport {
+    pub imported: u32,
+    pub lines: Vec<ImportLine>,
+}
+
+/// the comments seen since the last key
+#[derive(Default)]
+struct Pending {
+    name: Option<String>,
+    created: Option<SystemTime>,
+    public: Option<String>,
+}
+
+/// how to import one key line
+struct LineOptions {
+    /// used when there's no `# name:` comment above the key
+    fallback_name: String,
+    comments: Pending,
+    public_only: bool,
+}
+
+/// the non-blank lines of `text` with their 1-based line numbers. an OpenSSH private key spans
+/// several lines, so it's joined into one entry at the line it starts on
+fn entries(text: &str) -> Vec<(u32, String)> {
+    let mut entries: Vec<(u32, String)> = vec![];
+    let mut in_ssh_key = false;
+    for (number, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if in_ssh_key {
+            let (_, pem) = entries.last_mut().expect("an ssh key was started");
+            pem.push('\n');
+            pem.push_str(line);
+            in_ssh_key = line != ssh::PEM_END;
+        } else if !line.is_empty() {
+            in_ssh_key = line == ssh::PEM_BEGIN;
+            entries.push((number as u32 + 1, line.to_string()));
+        }
+    }
+    entries
+}
+
+/// days from 1970-01-01 to a date in the proleptic gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+/// how many days `month` has in `year`, counting leap years
+fn days_in_month(year: i64, month: i64) -> i64 {
+    match month {
+        2 if year % 4 == 0 && (year % 100 != 0 || year % 400 == 0) => 29,
+        2 => 28,
+        4 | 6 | 9 | 11 => 30,
+        _ => 31,
+    }
+}
+
+/// parse an RFC 3339 timestamp like `2006-01-02T15:04:05Z` or `2006-01-02T15:04:05.123+07:00`
+fn parse_rfc3339(text: &str) -> Option<SystemTime> {
+    let text = text.trim();
+    if !text.is_ascii() || text.len() < 20 {
+        return None;
+    }
+    let (datetime, zone) = text.split_at(19);
+    let bytes = datetime.as_bytes();
+    if bytes[4] != b'-'
+        || bytes[7] != b'-'
+        || !matches!(bytes[10], b'T' | b't' | b' ')
+        || bytes[13] != b':'
+        || bytes[16] != b':'
+    {
+        return None;
+    }
+    // `parse` on its own would take a sign, like `+1`
+    let number = |text: &str| {
+        text.bytes()
+            .all(|byte| byte.is_ascii_digit())
+            .then(|| text.parse::<i64>().ok())
+            .flatten()
+    };
+    let field = |range: std::ops::Range<usize>| number(&datetime[range]);
+    let (year, month, day) = (field(0..4)?, field(5..7)?, field(8..10)?);
+    let (hour, minute, second) = (field(11..13)?, field(14..16)?, field(17..19)?);
+    // a second of 60 is a leap second
+    if !(1..=12).contains(&month)
+        || !(1..=days_in_month(year, month)).contains(&day)
+        || hour > 23
+        || minute > 59
+        || second > 60
+    {
+        return None;
+    }
+
+    // fractional seconds don't matter for a creation date
+    let zone = match zone.strip_prefix('.') {
+        Some(rest) => {
+            let zone = rest.trim_start_matches(|c: char| c.is_ascii_digit());
+            if zone.len() == rest.len() {
+                return None;
+            }
+            zone
+        }
+        None => zone,
+    };
+    let offset = match zone {
+        "Z" | "z" => 0,
+        _ if zone.len() == 6 && &zone[3..4] == ":" => {
+            let sign = match &zone[0..1] {
+                "+" => 1,
+                "-" => -1,
+                _ => return None,
+            };
+            let hours = number(&zone[1..3])?;
+            let minutes = number(&zone[4..6])?;
+            if hours > 23 || minutes > 59 {
+                return None;
+            }
+            sign * (hours * 3600 + minutes * 60)
+        }
+        _ => return None,
+    };
+
+    let secs =
+        days_from_civil(year, month, day) * 86400 + hour * 3600 + minute * 60 + second - offset;
+    u64::try_from(secs).ok().map(unix_time)
+}
+
+impl Vault {
+    /// import every key in `text`. keys without a `# name:` comment are called `name`, numbered if there
+    /// is more than one. with `public_only`, secret keys are refused rather than imported. protected
+    /// OpenSSH keys are stored as they are, still protected. nothing is added unless the whole text
+    /// could be processed
+    pub fn import_keys(
+        &mut self,
+        name: &str,
+        text: &str,
+        expires_at: Option<SystemTime>,
+        public_only: bool,
+    ) -> Result<ImportReport, String> {
+        self.ensure_writable()?;
+        let entries = entries(text);
+        let key_count = entries
+            .iter()
+            .filter(|(_, line)| !line.starts_with('#'))
+            .count();
+        let mut pending = Pending::default();
+        let mut new_keys: Vec<KeyMetadata> = vec![];
+        let mut lines = vec![];
+        let mut index = 0;
+
+        for (number, line) in entries {
+            if let Some(comment) = line.strip_prefix('#') {
+                let comment = comment.trim();
+                // `age-keygen` writes `created:` and `public key:`, plugins like
+                // `age-plugin-yubikey` write `Name:` and `Recipient:`
+                if let Some(name) = comment
+                    .strip_prefix("name:")
+                    .or_else(|| comment.strip_prefix("Name:"))
+                {
+                    let name = name.trim();
+                    pending.name = (!name.is_empty()).then(|| name.to_string());
+                } else if let Some(date) = comment.strip_prefix("created:") {
+                    pending.created = parse_rfc3339(date);
+                } else if let Some(public) = comment
+                    .strip_prefix("public key:")
+                    .or_else(|| comment.strip_prefix("Recipient:"))
+                {
+                    pending.public = Some(public.trim().to_string());
+                }
+                lines.push(ImportLine {
+                    line: number,
+                    outcome: ImportOutcome::Skipped("comment".to_string()),
+                });
+                continue;
+            }
+
+            index += 1;
+            let comments = std::mem::take(&mut pending);
+            let name = if key_count > 1 {
+                format!("{name} ({index})")
+            } else {
+                name.to_string()
+            };
+            let options = LineOptions {
+                fallback_name: name,
+                comments,
+                public_only,
+            };
+            let outcome = match self.parse_key_line(&line, options, &new_keys)? {
+                Ok(mut key) => {
+                    key.expires_at = expires_at;
+                    let id = key.id.clone();
+                    new_keys.push(key);
+                    ImportOutcome::Imported(id)
+                }
+                Err(outcome) => outcome,
+            };
+            lines.push(ImportLine {
+                line: number,
+                outcome,
+            });
+        }
+
+        let imported = new_keys.len() as u32;
+        for key in new_keys {
+            self.put_key(key)?;
+        }
+        Ok(ImportReport { imported, lines })
+    }
+
+    /// turn one key line into a new entry. the outer `Err` means the vault itself failed (e.g. it's
+    /// locked) and the import should stop; the inner one says why this line was skipped or invalid
+    fn parse_key_line(
+        &self,
+        line: &str,
+        options: LineOptions,
+        new_keys: &[KeyMetadata],
+    ) -> Result<Result<KeyMetadata, ImportOutcome>, String> {
+        let invalid = |error: String| Ok(Err(ImportOutcome::Invalid(error)));
+        let LineOptions {
+            fallback_name,
+            comments,
+            public_only,
+        } = options;
+        let is_private = line.starts_with("AGE-SECRET-KEY-")
+            || line.starts_with("AGE-PLUGIN-")
+            || line.starts_with(ssh::PEM_BEGIN);
+        if is_private && public_only {
+            return invalid("secret keys don't belong in a recipients file".to_string());
+        }
+        let mut key_comment = None;
+        let (public, private) = if line.starts_with(ssh::PEM_BEGIN) {
+            let identity = match SshIdentity::from_pem(line) {
+                Ok(identity) => identity,
+                Err(error) => return invalid(error),
+            };
+            let public = match identity.to_public() {
+                Ok(public) => public.to_string(),
+                Err(error) => return invalid(error),
+            };
+            (public, Some(identity.to_string()))
+        } else if line.starts_with("AGE-SECRET-KEY-") {
+            let identity = match WildcardIdentity::from_secret(SecretString::from(line.to_string()))
+            {
+                Ok(identity) => identity,
+                Err(error) => return invalid(error),
+            };
+            let public = match identity.to_public().and_then(|public| public.to_string()) {
+                Ok(public) => public,
+                Err(error) => return invalid(error),
+            };
+            (public, Some(identity.to_string()?))
+        } else if line.starts_with("AGE-PLUGIN-") {
+            let identity = match WildcardIdentity::from_secret(SecretString::from(line.to_string()))
+            {
+                Ok(identity) => identity,
+                Err(error) => return invalid(error),
+            };
+            // the recipient can't be worked out without the plugin, so it has to be in a comment
+            let Some(public) = comments.public.clone() else {
+                return invalid(
+                    "plugin identities need their recipient in a `# public key:` comment above them"
+                        .to_string(),
+                );
+            };
+            if !matches!(
+                WildcardRecipient::from_public(&public),
+                Ok(WildcardRecipient::Plugin(_))
+            ) {
+                return invalid(format!("{public} isn't a plugin recipient"));
+            }
+            (public, Some(identity.to_string()?))
+        } else if line.starts_with("age1") {
+            match WildcardRecipient::from_public(line).and_then(|public| public.to_string()) {
+                Ok(public) => (public, None),
+                Err(error) => return invalid(error),
+            }
+        } else if let Some((public, comment)) = ssh::parse_public_line(line) {
+            key_comment = comment;
+            match WildcardRecipient::from_public(&public).and_then(|public| public.to_string()) {
+                Ok(public) => (public, None),
+                Err(error) => return invalid(error),
+            }
+        } else {
+            return invalid("not an age key".to_string());
+        };
+
+        if comments
+            .public
+            .as_ref()
+            .is_some_and(|comment| *comment != public)
+        {
+            return invalid("the public key comment above this key doesn't match it".to_string());
+        }
+        if let Some(existing) = self
+            .file
+            .secrets
+            .values()
+            .chain(new_keys.iter())
+            .find(|key| key.contents.public == public)
+        {
+            return Ok(Err(ImportOutcome::Skipped(format!(
+                "already in the vault as \"{}\"",
+                existing.name
+            ))));
+        }
+
+        // a `#` comment above the key wins over the comment at the end of an ssh key
+        let name = comments
+            .name
+            .clone()
+            .or(key_comment)
+            .unwrap_or(fallback_name);
+        let mut key = self.new_key(name, public, private)?;
+        if let Some(created) = comments.created {
+            key.date_created = created;
+        }
+        Ok(Ok(key))
+    }
+
+    /// a recipients file with the public keys of `ids`, or of every key that can still be encrypted
+    /// to. each key has its name in a comment above it
+    pub fn recipients_file(&self, ids: Option<&[String]>) -> Result<String, String> {
+        let keys: Vec<&KeyMetadata> = match ids {
+            Some(ids) => {
+                // refuses expired and retired keys
+                self.recipient_keys(ids)?;
+                ids.iter().filter_map(|id| self.get_key(id)).collect()
+            }
+            None => {
+                let mut keys: Vec<&KeyMetadata> = self
+                    .file
+                    .secrets
+                    .values()
+                    .filter(|key| key.retired_at.is_none() && !key.is_expired())
+                    .collect();
+                keys.sort_by_key(|key| key.date_created);
+                keys
+            }
+        };
+        if keys.is_empty() {
+            return Err("there are no keys to export".to_string());
+        }
+        Ok(keys
+            .iter()
+            .map(|key| {
+                format!(
+                    "# name: {}\n{}\n",
+                    key.name.replace(['\r', '\n'], " "),
+                    key.contents.public
+                )
+            })
+            .collect())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use age::x25519::Identity;
+
+    fn new_vault(dir: &std::path::Path) -> Vault {
+        let path = dir.join("vault.cb");
+        Vault::create_vault(path.to_str().unwrap(), &SecretString::from("hunter2")).unwrap()
+    }
+
+    /// the names of the keys `report` imported, in order
+    fn imported_names(vault: &Vault, report: &ImportReport) -> Vec<String> {
+        report
+            .lines
+            .iter()
+            .filter_map(|line| match &line.outcome {
+                ImportOutcome::Imported(id) => Some(vault.get_key(id).unwrap().name.clone()),
+                _ => None,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn takes_names_only_from_name_comments() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut vault = new_vault(dir.path());
+        let (first, second) = (Identity::generate(), Identity::generate());
+        let text = format!(
+            "# team keys, updated weekly\n# name: alice\n# created: 2006-01-02T15:04:05Z\n{}\n# bob's old laptop\n{}\n",
+            first.to_public(),
+            second.to_public()
+        );
+        let report = vault.import_keys("team", &text, None, true).unwrap();
+        assert_eq!(imported_names(&vault, &report), ["alice", "team (2)"]);
+        let ImportOutcome::Imported(id) = &report.lines[3].outcome else {
+            panic!("the first key is imported");
+        };
+        assert_eq!(
+            vault.get_key(id).unwrap().date_created,
+            unix_time(1136214245)
+        );
+    }
+
+    #[test]
+    fn exports_import_back_with_their_names() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut vault = new_vault(dir.path());
+        let text = format!(
+            "# name: alice\n{}\n# name: bob\n{}\n",
+            Identity::generate().to_public(),
+            Identity::generate().to_public()
+        );
+        vault.import_keys("team", &text, None, true).unwrap();
+        let exported = vault.recipients_file(None).unwrap();
+        assert!(exported.contains("# name: alice\nage1"));
+
+        let other_dir = tempfile::tempdir().unwrap();
+        let mut other = new_vault(other_dir.path());
+        let report = other.import_keys("team", &exported, None, true).unwrap();
+        let mut names = imported_names(&other, &report);
+        names.sort();
+        assert_eq!(names, ["alice", "bob"]);
+    }
+
+    #[test]
+    fn parses_utc_and_offset_timestamps() {
+        assert_eq!(
+            parse_rfc3339("2006-01-02T15:04:05Z"),
+            Some(unix_time(1136214245))
+        );
+        assert_eq!(
+            parse_rfc3339("2006-01-02T15:04:05.123+07:00"),
+            Some(unix_time(1136214245 - 7 * 3600))
+        );
+        assert_eq!(
+            parse_rfc3339("2006-01-02 15:04:05-01:30"),
+            Some(unix_time(1136214245 + 5400))
+        );
+        assert_eq!(parse_rfc3339("1970-01-01T00:00:00Z"), Some(unix_time(0)));
+    }
+
+    #[test]
+    fn rejects_signs_inside_fields() {
+        for text in [
+            "+006-01-02T15:04:05Z",
+            "2006-+1-02T15:04:05Z",
+            "2006-01-+2T15:04:05Z",
+            "2006-01-02T+5:04:05Z",
+            "2006-01-02T15:-4:05Z",
+            "2006-01-02T15:04:+5Z",
+            "2006-01-02T15:04:05+-1:00",
+            "2006-01-02T15:04:05+01:+0",
+            "2006-01-02T15:04:05.Z",
+        ] {
+            assert_eq!(parse_rfc3339(text), None, "{text}");
+        }
+    }
+
+    #[test]
+    fn accepts_leap_days_only_in_leap_years() {
+        assert_eq!(
+            parse_rfc3339("2024-02-29T00:00:00Z"),
+            Some(unix_time(1709164800))
+        );
+        assert!(parse_rfc3339("2000-02-29T00:00:00Z").is_some());
+        assert_eq!(parse_rfc3339("2023-02-29T00:00:00Z"), None);
+        assert_eq!(parse_rfc3339("2100-02-29T00:00:00Z"), None);
+    }
+
+    #[test]
+    fn rejects_impossible_dates() {
+        for text in [
+            "2024-02-31T00:00:00Z",
+            "2024-04-31T00:00:00Z",
+            "2024-13-01T00:00:00Z",
+            "2024-00-10T00:00:00Z",
+            "2024-01-00T00:00:00Z",
+            "2024-01-01T24:00:00Z",
+            "2024-01-01T00:60:00Z",
+            "2024-01-01T00:00:00+24:00",
+            "2024-01-01T00:00:00",
+            "2024-01-01",
+            "1969-12-31T23:59:59Z",
+        ] {
+            assert_eq!(parse_rfc3339(text), None, "{text}");
+        }
+    }
+}
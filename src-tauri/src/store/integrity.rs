@@ -0,0 +1,164 @@
+// per-entry integrity checks.
+// the whole-file HMAC only says *that* something changed. alongside it, the vault stores a MAC for
+// every entry plus one for the header, so a failed check can say *which* entries changed.
+// until the user picks a `RepairAction`, a vault that failed its check is read-only.
+use hmac::Mac;
+use secrecy::SecretBox;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+
+use super::{mac_key, HmacSha256, KeyMetadata, Vault, VaultFile};
+
+const ENTRY_MAC_LABEL: &[u8] = b"chiffrage entry hmac";
+
+/// a MAC for each entry, plus one for the header
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EntryMacs {
+    /// covers the salt, argon2 parameters, lock settings, `hello` and the ids of every entry
+    header: Vec<u8>,
+    entries: BTreeMap<String, Vec<u8>>,
+}
+
+/// what changed in a vault since it was last saved. built when the vault fails its integrity check.
+/// entries are redacted.
+#[derive(Serialize, Debug, Clone, specta::Type)]
+pub struct IntegrityReport {
+    pub added: Vec<KeyMetadata>,
+    pub modified: Vec<KeyMetadata>,
+    /// ids of entries that are gone
+    pub removed: Vec<String>,
+    /// the salt, argon2 parameters, lock settings, `hello` or the list of entries changed
+    pub header_modified: bool,
+    /// the vault was last saved without per-entry MACs, so the changes can't be narrowed down
+    pub unverifiable: bool,
+}
+
+/// how to get a vault that failed its integrity check writable again.
+/// either way, public-only keys that weren't flagged are kept, and the public half of every
+/// private key is re-derived from the private key.
+#[derive(Serialize, Deserialize, Debug, Clone, specta::Type)]
+pub enum RepairAction {
+    /// trust the vault as it is now
+    KeepAll,
+    /// delete every added or modified entry
+    RemoveFlagged,
+}
+
+fn new_mac(key: &SecretBox<[u8; 32]>) -> HmacSha256 {
+    <HmacSha256 as Mac>::new_from_slice(&mac_key(key, ENTRY_MAC_LABEL)[..])
+        .expect("hmac accepts any key length")
+}
+
+fn header_mac(key: &SecretBox<[u8; 32]>, file: &VaultFile) -> Result<HmacSha256, String> {
+    let ids: Vec<&String> = file.secrets.keys().collect();
+    let mut mac = new_mac(key);
+    mac.update(
+        &serde_cbor::to_vec(&(&file.salt, &file.kdf, &file.lock, &file.hello, ids))
+            .map_err(|e| e.to_string())?,
+    );
+    Ok(mac)
+}
+
+fn entry_mac(key: &SecretBox<[u8; 32]>, entry: &KeyMetadata) -> Result<HmacSha256, String> {
+    let mut mac = new_mac(key);
+    mac.update(&serde_cbor::to_vec(entry).map_err(|e| e.to_string())?);
+    Ok(mac)
+}
+
+impl Vault {
+    pub(super) fn compute_entry_macs(&self) -> Result<EntryMacs, String> {
+        let key = self.get_vault_key()?;
+        let mut entries = BTreeMap::new();
+        for (id, entry) in self.file.secrets.iter() {
+            entries.insert(
+                id.clone(),
+                entry_mac(key, entry)?.finalize().into_bytes().to_vec(),
+            );
+        }
+        Ok(EntryMacs {
+            header: header_mac(key, &self.file)?
+                .finalize()
+                .into_bytes()
+                .to_vec(),
+            entries,
+        })
+    }
+
+    /// compare every entry against the MACs it was saved with
+    pub(super) fn integrity_report(&self) -> Result<IntegrityReport, String> {
+        let key = self.get_vault_key()?;
+        let Some(macs) = self.file.entry_macs.as_ref() else {
+            return Ok(IntegrityReport {
+                added: vec![],
+                modified: vec![],
+                removed: vec![],
+                header_modified: false,
+                unverifiable: true,
+            });
+        };
+
+        let mut added = vec![];
+        let mut modified = vec![];
+        for (id, entry) in self.file.secrets.iter() {
+            match macs.entries.get(id) {
+                None => added.push(entry.clone().redacted()),
+                Some(stored) if entry_mac(key, entry)?.verify_slice(stored).is_err() => {
+                    modified.push(entry.clone().redacted())
+                }
+                Some(_) => {}
+            }
+        }
+        let removed = macs
+            .entries
+            .keys()
+            .filter(|id| !self.file.secrets.contains_key(*id))
+            .cloned()
+            .collect();
+
+        Ok(IntegrityReport {
+            added,
+            modified,
+            removed,
+            header_modified: header_mac(key, &self.file)?
+                .verify_slice(&macs.header)
+                .is_err(),
+            unverifiable: false,
+        })
+    }
+
+    /// if the vault failed its integrity check, it is read-only until it's repaired
+    pub fn integrity_problem(&self) -> Option<&IntegrityReport> {
+        self.read_only.as_ref()
+    }
+
+    pub(super) fn ensure_writable(&self) -> Result<(), String> {
+        match self.read_only {
+            Some(_) => Err(
+                "the vault failed its integrity check. choose how to repair it first".to_string(),
+            ),
+            None => Ok(()),
+        }
+    }
+
+    pub fn repair(&mut self, action: RepairAction) -> Result<(), String> {
+        let report = self
+            .read_only
+            .take()
+            .ok_or("the vault does not need to be repaired".to_string())?;
+        let file = self.file.clone();
+
+        if matches!(action, RepairAction::RemoveFlagged) {
+            for entry in report.added.iter().chain(report.modified.iter()) {
+                self.file.secrets.remove(&entry.id);
+            }
+        }
+        let result = self
+            .regenerate_public_identities()
+            .and_then(|_| self.save_vault());
+        if result.is_err() {
+            self.file = file;
+            self.read_only = Some(report);
+        }
+        result
+    }
+}
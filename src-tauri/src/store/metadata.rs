@@ -0,0 +1,145 @@
+// editing and searching the descriptive parts of a key: its name, owner email, notes and tags.
+// the key material itself never changes after import.
+use serde::Deserialize;
+use std::time::{Duration, SystemTime};
+
+use super::{KeyMetadata, Vault};
+
+/// the new name, email, notes and tags for a key. every field replaces the old value
+#[derive(Deserialize, Debug, Clone, specta::Type)]
+pub struct KeyMetadataUpdate {
+    pub name: String,
+    pub email: Option<String>,
+    pub notes: Option<String>,
+    pub tags: Vec<String>,
+}
+
+/// keys match if they have every one of `tags`, and `text` (if set) appears in their name, email,
+/// notes or tags. both are case-insensitive.
+#[derive(Deserialize, Debug, Clone, specta::Type)]
+pub struct KeySearch {
+    pub text: Option<String>,
+    pub tags: Vec<String>,
+}
+
+/// trim a text field, treating an empty one as unset
+fn clean(field: Option<String>) -> Option<String> {
+    field
+        .map(|field| field.trim().to_string())
+        .filter(|field| !field.is_empty())
+}
+
+/// tags are trimmed, lowercased, deduplicated and sorted
+fn clean_tags(tags: Vec<String>) -> Vec<String> {
+    let mut tags: Vec<String> = tags
+        .into_iter()
+        .map(|tag| tag.trim().to_lowercase())
+        .filter(|tag| !tag.is_empty())
+        .collect();
+    tags.sort();
+    tags.dedup();
+    tags
+}
+
+impl KeySearch {
+    fn matches(&self, key: &KeyMetadata) -> bool {
+        if !clean_tags(self.tags.clone())
+            .iter()
+            .all(|tag| key.tags.contains(tag))
+        {
+            return false;
+        }
+        let Some(text) = clean(self.text.clone()) else {
+            return true;
+        };
+        let text = text.to_lowercase();
+        [Some(&key.name), key.email.as_ref(), key.notes.as_ref()]
+            .into_iter()
+            .flatten()
+            .chain(key.tags.iter())
+            .any(|field| field.to_lowercase().contains(&text))
+    }
+}
+
+impl Vault {
+    pub fn update_key_metadata(
+        &mut self,
+        id: &str,
+        update: KeyMetadataUpdate,
+    ) -> Result<KeyMetadata, String> {
+        self.ensure_writable()?;
+        let name = update.name.trim().to_string();
+        if name.is_empty() {
+            return Err("no name set".to_string());
+        }
+        let key = self
+            .file
+            .secrets
+            .get_mut(id)
+            .ok_or("key does not exist".to_string())?;
+        key.name = name;
+        key.email = clean(update.email);
+        key.notes = clean(update.notes);
+        key.tags = clean_tags(update.tags);
+        key.date_modified = SystemTime::now();
+        Ok(key.clone().redacted())
+    }
+
+    /// the public keys of `ids`, for encrypting to. fails if any of them has expired or been retired
+    pub fn recipient_keys(&self, ids: &[String]) -> Result<Vec<String>, String> {
+        let mut expired = vec![];
+        let mut retired = vec![];
+        let mut public_keys = Vec::with_capacity(ids.len());
+        for id in ids {
+            let key = self.get_key(id).ok_or("key does not exist".to_string())?;
+            if key.retired_at.is_some() {
+                retired.push(key.name.clone());
+            } else if key.is_expired() {
+                expired.push(key.name.clone());
+            }
+            public_keys.push(key.contents.public.clone());
+        }
+        if !retired.is_empty() {
+            return Err(format!(
+                "cannot encrypt to retired keys: {}. use the keys they were rotated to",
+                retired.join(", ")
+            ));
+        }
+        if !expired.is_empty() {
+            return Err(format!(
+                "cannot encrypt to expired keys: {}",
+                expired.join(", ")
+            ));
+        }
+        Ok(public_keys)
+    }
+
+    /// keys that expire within `within` (or already have), soonest first. redacted
+    pub fn expiring_keys(&self, within: Duration) -> Vec<KeyMetadata> {
+        let cutoff = SystemTime::now() + within;
+        let mut keys: Vec<KeyMetadata> = self
+            .file
+            .secrets
+            .values()
+            .filter(|key| {
+                key.expires_at
+                    .is_some_and(|expires_at| expires_at <= cutoff)
+            })
+            .cloned()
+            .map(KeyMetadata::redacted)
+            .collect();
+        keys.sort_by_key(|key| key.expires_at);
+        keys
+    }
+
+    /// every key matching `search`, redacted
+    pub fn search_keys(&self, search: &KeySearch) -> Vec<KeyMetadata> {
+        self.file
+            .secrets
+            .values()
+            .filter(|key| search.matches(key))
+            .cloned()
+            .map(KeyMetadata::redacted)
+            .collect()
+    }
+}
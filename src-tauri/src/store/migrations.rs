@@ -0,0 +1,572 @@
+// versioning for the vault file format.
+// on disk, the vault is a `version` number wrapped around the actual `VaultFile`.
+// files from before versioning existed are a bare `VaultFile`, and count as version 0.
+// from version 2 on, the `VaultFile` is stored as a CBOR byte string next to an HMAC of those exact bytes,
+// so the HMAC still checks out after the file has been migrated in memory.
+// to change the layout of `VaultFile`, bump `CURRENT_VERSION` and add a migration to `MIGRATIONS`.
+use serde::{Deserialize, Serialize};
+use serde_cbor::Value;
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use super::throttle::UnlockAttempts;
+use super::{KdfParams, LockSettings, StoredMac, VaultFile, VaultLoadError};
+
+pub const CURRENT_VERSION: u32 = 9;
+
+/// takes a vault in the layout of one version and returns it in the layout of the next
+type Migration = fn(Value) -> Result<Value, String>;
+
+/// `MIGRATIONS[n]` upgrades a version `n` vault to version `n + 1`
+const MIGRATIONS: [Migration; CURRENT_VERSION as usize] = [
+    v0_to_v1, v1_to_v2, v2_to_v3, v3_to_v4, v4_to_v5, v5_to_v6, v6_to_v7, v7_to_v8, v8_to_v9,
+];
+
+#[derive(Serialize, Deserialize)]
+struct VaultEnvelope {
+    version: u32,
+    vault: Value,
+    #[serde(default)]
+    hmac: Option<Value>,
+    /// not covered by the HMAC. see `throttle`
+    #[serde(default)]
+    attempts: UnlockAttempts,
+}
+
+/// the CBOR encoding of `file`. this is the exact byte string the vault HMAC covers
+pub fn encode_body(file: &VaultFile) -> Result<Vec<u8>, String> {
+    serde_cbor::to_vec(file).map_err(|e| format!("failed to serialize vault: {e}"))
+}
+
+pub fn encode_envelope(
+    version: u32,
+    body: Vec<u8>,
+    hmac: Vec<u8>,
+    attempts: &UnlockAttempts,
+) -> Result<Vec<u8>, String> {
+    serde_cbor::to_vec(&VaultEnvelope {
+        version,
+        vault: Value::Bytes(body),
+        hmac: Some(Value::Bytes(hmac)),
+        attempts: attempts.clone(),
+    })
+    .map_err(|e| format!("failed to serialize vault: {e}"))
+}
+
+/// a vault file as it was before it was migrated. it's written next to the vault as
+/// `<name>.v<version>.bak` when the migrated vault is first saved over it, rather than on load, so a
+/// vault on read-only media still opens.
+pub struct PendingBackup {
+    version: u32,
+    data: Vec<u8>,
+}
+
+impl PendingBackup {
+    /// an existing backup is never overwritten, so the oldest copy of that version is kept.
+    pub fn write(&self, path: &Path) -> Result<(), String> {
+        let backup = backup_path(path, self.version);
+        if backup.exists() {
+            return Ok(());
+        }
+        std::fs::write(&backup, &self.data)
+            .map_err(|e| format!("failed to write vault backup: {e}"))
+    }
+}
+
+/// parse the contents of a vault file, migrating it to `CURRENT_VERSION` if needed. also returns the
+/// HMAC the file was stored with, along with the bytes it covers, so it can be checked on unlock,
+/// the failed unlock counter, and the file as it was if it had to be migrated.
+pub fn deserialize(
+    data: &[u8],
+) -> Result<(VaultFile, StoredMac, UnlockAttempts, Option<PendingBackup>), VaultLoadError> {
+    let value: Value =
+        serde_cbor::from_slice(data).map_err(|e| VaultLoadError::Corrupt(e.to_string()))?;
+    let (mut version, mut vault, stored_mac, attempts) =
+        match serde_cbor::value::from_value::<VaultEnvelope>(value.clone()) {
+            Ok(VaultEnvelope {
+                version,
+                vault: Value::Bytes(body),
+                hmac: Some(Value::Bytes(hmac)),
+                attempts,
+            }) if version >= 2 => {
+                let vault = serde_cbor::from_slice(&body)
+                    .map_err(|e| VaultLoadError::Corrupt(e.to_string()))?;
+                (
+                    version,
+                    vault,
+                    StoredMac::FullFile {
+                        version,
+                        body,
+                        hmac,
+                    },
+                    attempts,
+                )
+            }
+            Ok(envelope) if envelope.version >= 2 => {
+                return Err(VaultLoadError::Corrupt("vault has no HMAC".to_string()))
+            }
+            Ok(envelope) => {
+                let stored_mac = legacy_mac(&envelope.vault)?;
+                (
+                    envelope.version,
+                    envelope.vault,
+                    stored_mac,
+                    envelope.attempts,
+                )
+            }
+            Err(_) => {
+                // no envelope, so it's from before versioning
+                let stored_mac = legacy_mac(&value)?;
+                (0, value, stored_mac, UnlockAttempts::default())
+            }
+        };
+
+    if version > CURRENT_VERSION {
+        return Err(VaultLoadError::UnsupportedVersion(version));
+    }
+
+    let backup = (version < CURRENT_VERSION).then(|| PendingBackup {
+        version,
+        data: data.to_vec(),
+    });
+    while version < CURRENT_VERSION {
+        vault = MIGRATIONS[version as usize](vault).map_err(|e| {
+            VaultLoadError::Corrupt(format!("failed to migrate from version {version}: {e}"))
+        })?;
+        version += 1;
+    }
+
+    let file: VaultFile =
+        serde_cbor::value::from_value(vault).map_err(|e| VaultLoadError::Corrupt(e.to_string()))?;
+    file.kdf.check_limits().map_err(VaultLoadError::Corrupt)?;
+    Ok((file, stored_mac, attempts, backup))
+}
+
+fn backup_path(path: &Path, version: u32) -> PathBuf {
+    let mut name = path.file_name().unwrap_or_default().to_os_string();
+    name.push(format!(".v{version}.bak"));
+    path.with_file_name(name)
+}
+
+/// delete the copies kept from before each migration
+pub fn delete_backups(path: &Path) -> Result<(), String> {
+    let mut result = Ok(());
+    for version in 0..CURRENT_VERSION {
+        match std::fs::remove_file(backup_path(path, version)) {
+            Err(e) if e.kind() != std::io::ErrorKind::NotFound => {
+                result = Err(format!("failed to delete vault backup: {e}"));
+            }
+            _ => {}
+        }
+    }
+    result
+}
+
+fn as_map(vault: Value) -> Result<BTreeMap<Value, Value>, String> {
+    match vault {
+        Value::Map(map) => Ok(map),
+        _ => Err("vault is not a map".to_string()),
+    }
+}
+
+/// the layout of `secrets` up to version 1. the HMAC of those versions covers exactly the CBOR
+/// encoding of these types, so they must never change.
+mod legacy {
+    use serde::{Deserialize, Serialize};
+    use std::time::SystemTime;
+
+    #[derive(Serialize, Deserialize)]
+    pub enum KeyType {
+        Public,
+        Private,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct KeyMetadata {
+        pub id: String,
+        pub name: String,
+        pub key_type: KeyType,
+        pub date_created: SystemTime,
+        pub contents: KeyPair,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct KeyPair {
+        pub public: String,
+        pub private: Option<EncryptedSecret>,
+    }
+
+    #[derive(Serialize, Deserialize)]
+    pub struct EncryptedSecret {
+        pub nonce: Vec<u8>,
+        pub ciphertext: Vec<u8>,
+    }
+}
+
+/// pull the secrets-only HMAC out of a version 0 or 1 vault
+fn legacy_mac(vault: &Value) -> Result<StoredMac, VaultLoadError> {
+    let corrupt = |e: String| VaultLoadError::Corrupt(e);
+    let map = as_map(vault.clone()).map_err(corrupt)?;
+    let secrets = map
+        .get(&Value::Text("secrets".to_string()))
+        .cloned()
+        .ok_or(corrupt("vault has no secrets".to_string()))?;
+    let secrets: BTreeMap<String, legacy::KeyMetadata> =
+        serde_cbor::value::from_value(secrets).map_err(|e| corrupt(e.to_string()))?;
+    let hmac = match map.get(&Value::Text("hmac".to_string())) {
+        Some(hmac) => serde_cbor::value::from_value::<Option<Vec<u8>>>(hmac.clone())
+            .map_err(|e| corrupt(e.to_string()))?,
+        None => None,
+    };
+    Ok(StoredMac::SecretsOnly {
+        secrets: serde_cbor::to_vec(&secrets).map_err(|e| corrupt(e.to_string()))?,
+        hmac,
+    })
+}
+
+/// version 0 didn't store argon2 parameters. every one of those vaults was derived with `KdfParams::legacy()`
+fn v0_to_v1(vault: Value) -> Result<Value, String> {
+    let mut vault = as_map(vault)?;
+    let kdf = serde_cbor::value::to_value(KdfParams::legacy()).map_err(|e| e.to_string())?;
+    vault.entry(Value::Text("kdf".to_string())).or_insert(kdf);
+    Ok(Value::Map(vault))
+}
+
+/// version 2 moved the HMAC out of the vault and into the envelope
+fn v1_to_v2(vault: Value) -> Result<Value, String> {
+    let mut vault = as_map(vault)?;
+    vault.remove(&Value::Text("hmac".to_string()));
+    Ok(Value::Map(vault))
+}
+
+fn mark_unbound(secret: &mut Value) -> Result<(), String> {
+    match secret {
+        Value::Map(secret) => {
+            secret.insert(Value::Text("bound".to_string()), Value::Bool(false));
+            Ok(())
+        }
+        Value::Null => Ok(()),
+        _ => Err("encrypted secret is not a map".to_string()),
+    }
+}
+
+/// version 3 binds private keys to their entries with associated data. nothing from before that is bound
+fn v2_to_v3(vault: Value) -> Result<Value, String> {
+    let mut vault = as_map(vault)?;
+    if let Some(hello) = vault.get_mut(&Value::Text("hello".to_string())) {
+        mark_unbound(hello)?;
+    }
+    if let Some(Value::Map(secrets)) = vault.get_mut(&Value::Text("secrets".to_string())) {
+        for key in secrets.values_mut() {
+            let Value::Map(key) = key else {
+                return Err("key entry is not a map".to_string());
+            };
+            if let Some(Value::Map(contents)) = key.get_mut(&Value::Text("contents".to_string())) {
+                if let Some(private) = contents.get_mut(&Value::Text("private".to_string())) {
+                    mark_unbound(private)?;
+                }
+            }
+        }
+    }
+    Ok(Value::Map(vault))
+}
+
+/// version 4 added per-entry MACs. they're filled in on the next save
+fn v3_to_v4(vault: Value) -> Result<Value, String> {
+    let mut vault = as_map(vault)?;
+    vault
+        .entry(Value::Text("entry_macs".to_string()))
+        .or_insert(Value::Null);
+    Ok(Value::Map(vault))
+}
+
+/// version 5 added auto-lock settings
+fn v4_to_v5(vault: Value) -> Result<Value, String> {
+    let mut vault = as_map(vault)?;
+    let lock = serde_cbor::value::to_value(LockSettings::default()).map_err(|e| e.to_string())?;
+    vault.entry(Value::Text("lock".to_string())).or_insert(lock);
+    Ok(Value::Map(vault))
+}
+
+/// version 6 added optional email, notes and tags to each key, and when they last changed
+fn v5_to_v6(vault: Value) -> Result<Value, String> {
+    let mut vault = as_map(vault)?;
+    if let Some(Value::Map(secrets)) = vault.get_mut(&Value::Text("secrets".to_string())) {
+        for key in secrets.values_mut() {
+            let Value::Map(key) = key else {
+                return Err("key entry is not a map".to_string());
+            };
+            let date_created = key
+                .get(&Value::Text("date_created".to_string()))
+                .cloned()
+                .ok_or("key entry has no creation date".to_string())?;
+            key.entry(Value::Text("email".to_string()))
+                .or_insert(Value::Null);
+            key.entry(Value::Text("notes".to_string()))
+                .or_insert(Value::Null);
+            key.entry(Value::Text("tags".to_string()))
+                .or_insert(Value::Array(vec![]));
+            key.entry(Value::Text("date_modified".to_string()))
+                .or_insert(date_created);
+        }
+    }
+    Ok(Value::Map(vault))
+}
+
+/// version 7 added expiry dates. existing keys never expire
+fn v6_to_v7(vault: Value) -> Result<Value, String> {
+    let mut vault = as_map(vault)?;
+    if let Some(Value::Map(secrets)) = vault.get_mut(&Value::Text("secrets".to_string())) {
+        for key in secrets.values_mut() {
+            let Value::Map(key) = key else {
+                return Err("key entry is not a map".to_string());
+            };
+            key.entry(Value::Text("expires_at".to_string()))
+                .or_insert(Value::Null);
+        }
+    }
+    Ok(Value::Map(vault))
+}
+
+/// version 8 added key rotation. nothing from before it has been retired
+fn v7_to_v8(vault: Value) -> Result<Value, String> {
+    let mut vault = as_map(vault)?;
+    if let Some(Value::Map(secrets)) = vault.get_mut(&Value::Text("secrets".to_string())) {
+        for key in secrets.values_mut() {
+            let Value::Map(key) = key else {
+                return Err("key entry is not a map".to_string());
+            };
+            for field in ["retired_at", "successor", "predecessor"] {
+                key.entry(Value::Text(field.to_string()))
+                    .or_insert(Value::Null);
+            }
+        }
+    }
+    Ok(Value::Map(vault))
+}
+
+/// version 9 started recording private key reveals. there's no record of earlier ones
+fn v8_to_v9(vault: Value) -> Result<Value, String> {
+    let mut vault = as_map(vault)?;
+    if let Some(Value::Map(secrets)) = vault.get_mut(&Value::Text("secrets".to_string())) {
+        for key in secrets.values_mut() {
+            let Value::Map(key) = key else {
+                return Err("key entry is not a map".to_string());
+            };
+            key.entry(Value::Text("reveals".to_string()))
+                .or_insert(Value::Array(vec![]));
+        }
+    }
+    Ok(Value::Map(vault))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::{HmacSha256, Vault};
+    use super::*;
+    use hmac::Mac;
+    use secrecy::{ExposeSecret, SecretString};
+    use std::time::{Duration, SystemTime};
+
+    /// a vault as it was saved before versioning
+    #[derive(Serialize)]
+    struct V0Vault {
+        salt: Vec<u8>,
+        hello: legacy::EncryptedSecret,
+        secrets: BTreeMap<String, legacy::KeyMetadata>,
+        hmac: Option<Vec<u8>>,
+    }
+
+    const CREATED: u64 = 1_700_000_000;
+
+    fn v0_vault() -> Vec<u8> {
+        let secret = || legacy::EncryptedSecret {
+            nonce: vec![1; 24],
+            ciphertext: vec![2; 48],
+        };
+        let key = legacy::KeyMetadata {
+            id: "k1".to_string(),
+            name: "laptop".to_string(),
+            key_type: legacy::KeyType::Private,
+            date_created: SystemTime::UNIX_EPOCH + Duration::from_secs(CREATED),
+            contents: legacy::KeyPair {
+                public: "age1public".to_string(),
+                private: Some(secret()),
+            },
+        };
+        serde_cbor::to_vec(&V0Vault {
+            salt: vec![3; 16],
+            hello: secret(),
+            secrets: BTreeMap::from([("k1".to_string(), key)]),
+            hmac: Some(vec![4; 32]),
+        })
+        .unwrap()
+    }
+
+    #[test]
+    fn migrates_a_version_0_vault_to_the_current_layout() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vault.cbor");
+        let data = v0_vault();
+
+        let (file, stored_mac, attempts, backup) = deserialize(&data).unwrap();
+        assert_eq!(file.kdf, KdfParams::legacy());
+        assert_eq!(file.lock, LockSettings::default());
+        assert!(!file.hello.bound);
+        assert!(file.entry_macs.is_none());
+        let key = &file.secrets["k1"];
+        assert_eq!(key.name, "laptop");
+        assert!(!key.contents.private.as_ref().unwrap().bound);
+        assert_eq!(
+            key.date_modified,
+            SystemTime::UNIX_EPOCH + Duration::from_secs(CREATED)
+        );
+        assert!(key.email.is_none() && key.notes.is_none() && key.tags.is_empty());
+        assert!(key.expires_at.is_none() && key.retired_at.is_none());
+        assert!(key.successor.is_none() && key.predecessor.is_none());
+        assert!(key.reveals.is_empty());
+        assert!(matches!(
+            stored_mac,
+            StoredMac::SecretsOnly { hmac: Some(hmac), .. } if hmac == vec![4; 32]
+        ));
+        assert_eq!(attempts.failures, 0);
+
+        // nothing is written until the migrated vault is saved
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 0);
+        backup.unwrap().write(&path).unwrap();
+        assert_eq!(std::fs::read(backup_path(&path, 0)).unwrap(), data);
+        delete_backups(&path).unwrap();
+        assert!(!backup_path(&path, 0).exists());
+    }
+
+    #[test]
+    fn verifies_a_legacy_vault_and_upgrades_its_mac() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("vault.cbor");
+        let password = SecretString::from("hunter2");
+        let vault = Vault::create_vault(path.to_str().unwrap(), &password).unwrap();
+        let vault_key = vault.get_vault_key().unwrap();
+        let seal = |secret: &str| {
+            let sealed =
+                Vault::encrypt_secret(vault_key, SecretString::from(secret), None).unwrap();
+            legacy::EncryptedSecret {
+                nonce: sealed.nonce,
+                ciphertext: sealed.ciphertext,
+            }
+        };
+        let identity = age::x25519::Identity::generate();
+        let key = legacy::KeyMetadata {
+            id: "k1".to_string(),
+            name: "laptop".to_string(),
+            key_type: legacy::KeyType::Private,
+            date_created: SystemTime::UNIX_EPOCH + Duration::from_secs(CREATED),
+            contents: legacy::KeyPair {
+                public: identity.to_public().to_string(),
+                private: Some(seal(identity.to_string().expose_secret())),
+            },
+        };
+        let secrets = BTreeMap::from([("k1".to_string(), key)]);
+        let mut mac = <HmacSha256 as Mac>::new_from_slice(vault_key.expose_secret()).unwrap();
+        mac.update(&serde_cbor::to_vec(&secrets).unwrap());
+        let data = serde_cbor::to_vec(&V0Vault {
+            salt: vault.file.salt.clone(),
+            hello: seal("hello"),
+            secrets,
+            hmac: Some(mac.finalize().into_bytes().to_vec()),
+        })
+        .unwrap();
+        std::fs::write(&path, &data).unwrap();
+
+        let mut legacy_vault = Vault::load_vault(path.to_str().unwrap()).unwrap();
+        legacy_vault.set_vault_key(password.clone()).unwrap();
+        assert!(legacy_vault.needs_mac_upgrade());
+        // what unlocking does once the old HMAC has checked out
+        legacy_vault.save_vault().unwrap();
+        assert_eq!(std::fs::read(backup_path(&path, 0)).unwrap(), data);
+
+        let mut upgraded = Vault::load_vault(path.to_str().unwrap()).unwrap();
+        assert!(matches!(
+            upgraded.stored_mac,
+            Some(StoredMac::FullFile {
+                version: CURRENT_VERSION,
+                ..
+            })
+        ));
+        upgraded.set_vault_key(password).unwrap();
+        let key = &upgraded.file.secrets["k1"];
+        assert!(key.contents.private.as_ref().unwrap().bound);
+        assert_eq!(
+            upgraded.decrypt_private_key(key).unwrap().expose_secret(),
+            identity.to_string().expose_secret()
+        );
+    }
+
+    #[test]
+    fn reads_the_current_version_back_without_a_backup() {
+        let (file, _, _, _) = deserialize(&v0_vault()).unwrap();
+
+        let body = encode_body(&file).unwrap();
+        let data = encode_envelope(
+            CURRENT_VERSION,
+            body.clone(),
+            vec![5; 32],
+            &UnlockAttempts::default(),
+        )
+        .unwrap();
+        let (again, stored_mac, _, backup) = deserialize(&data).unwrap();
+        assert_eq!(encode_body(&again).unwrap(), body);
+        assert!(matches!(
+            stored_mac,
+            StoredMac::FullFile { version: CURRENT_VERSION, body: stored, .. } if stored == body
+        ));
+        assert!(backup.is_none());
+    }
+
+    #[test]
+    fn refuses_kdf_params_over_the_limits() {
+        let (mut file, _, _, _) = deserialize(&v0_vault()).unwrap();
+        file.kdf.memory_cost = KdfParams::MAX_MEMORY_COST + 1;
+        let data = encode_envelope(
+            CURRENT_VERSION,
+            encode_body(&file).unwrap(),
+            vec![5; 32],
+            &UnlockAttempts::default(),
+        )
+        .unwrap();
+        assert!(matches!(
+            deserialize(&data),
+            Err(VaultLoadError::Corrupt(message)) if message.contains("too expensive")
+        ));
+    }
+
+    #[test]
+    fn refuses_newer_versions() {
+        let body = serde_cbor::to_vec(&Value::Map(BTreeMap::new())).unwrap();
+        let data = encode_envelope(
+            CURRENT_VERSION + 1,
+            body,
+            vec![5; 32],
+            &UnlockAttempts::default(),
+        )
+        .unwrap();
+        assert!(matches!(
+            deserialize(&data),
+            Err(VaultLoadError::UnsupportedVersion(version)) if version == CURRENT_VERSION + 1
+        ));
+    }
+
+    #[test]
+    fn refuses_a_versioned_vault_without_an_hmac() {
+        let data = serde_cbor::to_vec(&VaultEnvelope {
+            version: 2,
+            vault: Value::Bytes(vec![]),
+            hmac: None,
+            attempts: UnlockAttempts::default(),
+        })
+        .unwrap();
+        assert!(matches!(
+            deserialize(&data),
+            Err(VaultLoadError::Corrupt(message)) if message == "vault has no HMAC"
+        ));
+    }
+}
@@ -2,25 +2,49 @@
 // use the Vault struct to interface with it
 // you can open (or create) a vault with:
 // Vault::load_vault()
+mod backups;
 mod commands;
+mod fingerprint;
+mod import;
+mod integrity;
+mod metadata;
+mod migrations;
+mod profiles;
+mod reveal;
+mod rotation;
+mod throttle;
 use age::secrecy::zeroize::Zeroize;
 use age::x25519::{Identity, Recipient};
+pub use backups::VaultBackup;
 pub use commands::*;
+pub use fingerprint::fingerprint;
+pub use import::{ImportLine, ImportOutcome, ImportReport};
+pub use integrity::{IntegrityReport, RepairAction};
+pub use metadata::{KeyMetadataUpdate, KeySearch};
+pub use profiles::{VaultProfile, VaultProfiles};
 use region::{alloc, lock, LockGuard, Protection};
+pub use reveal::{KeyReveal, RevealKind};
 
-use argon2::{password_hash::rand_core::RngCore, Argon2};
+use argon2::{password_hash::rand_core::RngCore, Algorithm, Argon2, Params, Version};
 use chacha20poly1305::{
-    aead::{AeadMut, OsRng},
+    aead::{AeadMut, OsRng, Payload},
     AeadCore, KeyInit, XChaCha20Poly1305, XNonce,
 };
+use hkdf::Hkdf;
 use hmac::{Hmac, Mac};
+use secrecy::zeroize::Zeroizing;
 use sha2::Sha256;
 
 use cuid2::create_id;
-use secrecy::{ExposeSecret, SecretBox, SecretString};
+use secrecy::{ExposeSecret, ExposeSecretMut, SecretBox, SecretString};
 use serde::{Deserialize, Serialize};
 use std::collections::BTreeMap;
-use std::{fs, path::PathBuf, str::FromStr, time::SystemTime};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, SystemTime},
+};
 
 use crate::crypto::WildcardIdentity;
 use age_xwing::HybridIdentity;
@@ -39,26 +63,62 @@ pub struct KeyMetadata {
     pub key_type: KeyType,
     pub date_created: SystemTime,
     pub contents: KeyPair,
+    /// the owner's email address
+    pub email: Option<String>,
+    pub notes: Option<String>,
+    pub tags: Vec<String>,
+    /// when the name, email, notes or tags last changed
+    pub date_modified: SystemTime,
+    /// after this, the key can't be encrypted to
+    pub expires_at: Option<SystemTime>,
+    /// set once the key has been rotated. a retired key is only used to decrypt
+    pub retired_at: Option<SystemTime>,
+    /// the id of the key this one was rotated to
+    pub successor: Option<String>,
+    /// the id of the key this one replaced
+    pub predecessor: Option<String>,
+    /// every time the private key was shown or exported, oldest first
+    pub reveals: Vec<KeyReveal>,
+    /// see `fingerprint`. only filled in on keys sent to the frontend, never stored
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fingerprint: Option<String>,
 }
 
 impl KeyMetadata {
-    pub fn from_keypair(name: String, keypair: KeyPair) -> KeyMetadata {
+    pub fn from_keypair(id: String, name: String, keypair: KeyPair) -> KeyMetadata {
         let key_type = match keypair.private {
             Some(_) => KeyType::Private,
             None => KeyType::Public,
         };
+        let now = SystemTime::now();
         KeyMetadata {
-            id: create_id(),
+            id,
             name,
             key_type,
-            date_created: SystemTime::now(),
+            date_created: now,
             contents: keypair,
+            email: None,
+            notes: None,
+            tags: vec![],
+            date_modified: now,
+            expires_at: None,
+            retired_at: None,
+            successor: None,
+            predecessor: None,
+            reveals: vec![],
+            fingerprint: None,
         }
     }
 
-    /// removes the private key without changing `key_type`
+    pub fn is_expired(&self) -> bool {
+        self.expires_at
+            .is_some_and(|expires_at| expires_at <= SystemTime::now())
+    }
+
+    /// removes the private key without changing `key_type`, and fills in the fingerprint
     pub fn redacted(mut self) -> KeyMetadata {
         self.contents.redact();
+        self.fingerprint = Some(fingerprint::fingerprint(&self.contents.public));
         self
     }
 }
@@ -88,6 +148,10 @@ pub enum VaultStatusUpdate {
     VerificationFail,
     AuthenticationCancel,
     Locked,
+    /// too many wrong passwords. the next attempt is refused for `seconds`
+    LockedOut {
+        seconds: u64,
+    },
 }
 
 impl From<Recipient> for KeyPair {
@@ -99,36 +163,203 @@ impl From<Recipient> for KeyPair {
     }
 }
 
+/// why a vault file couldn't be loaded
+#[derive(Serialize, Debug, Clone, specta::Type)]
+#[serde(tag = "kind", content = "message", rename_all = "camelCase")]
+pub enum VaultLoadError {
+    Missing,
+    /// the file exists but couldn't be read
+    Unreadable(String),
+    /// the file isn't a vault, or is damaged
+    Corrupt(String),
+    /// the vault was saved by a newer version of the app. contains the format version
+    UnsupportedVersion(u32),
+}
+
+impl std::fmt::Display for VaultLoadError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Missing => write!(f, "vault does not exist"),
+            Self::Unreadable(error) => write!(f, "could not read vault: {error}"),
+            Self::Corrupt(error) => write!(f, "vault is damaged: {error}"),
+            Self::UnsupportedVersion(version) => write!(
+                f,
+                "this vault was created by a newer version of chiffrage (format version {version}). update the app to open it"
+            ),
+        }
+    }
+}
+
+impl From<VaultLoadError> for String {
+    fn from(error: VaultLoadError) -> String {
+        error.to_string()
+    }
+}
+
 /// a type storing an XChaCha20Poly1305 `ciphertext` and `nonce`. both are of type `Vec<u8>`.
+///
+/// `bound` is `true` if the ciphertext was sealed with its key entry as associated data (see `secret_aad`).
+/// private keys from before format version 3 aren't, and get re-sealed the next time the vault is saved.
 #[derive(Serialize, Deserialize, Debug, Clone, specta::Type)]
 pub struct EncryptedSecret {
     nonce: Vec<u8>,
     ciphertext: Vec<u8>,
+    bound: bool,
+}
+
+/// the associated data a private key is sealed with. a ciphertext moved to another entry won't decrypt
+fn secret_aad(id: &str, key_type: &KeyType) -> Vec<u8> {
+    format!("chiffrage:{id}:{key_type:?}").into_bytes()
+}
+
+/// the argon2 parameters a vault key is derived with. these are stored in the vault file so that
+/// a change to the defaults of the `argon2` crate can never lock anyone out of an existing vault.
+/// `memory_cost` is in KiB.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, specta::Type)]
+pub struct KdfParams {
+    pub algorithm: String,
+    pub version: u32,
+    pub memory_cost: u32,
+    pub time_cost: u32,
+    pub parallelism: u32,
+}
+
+impl KdfParams {
+    /// the parameters every vault was created with before they were stored in the file
+    /// (`Argon2::default()` as of argon2 0.5). don't change these.
+    pub fn legacy() -> KdfParams {
+        KdfParams {
+            algorithm: Algorithm::Argon2id.to_string(),
+            version: Version::V0x13 as u32,
+            memory_cost: 19 * 1024,
+            time_cost: 2,
+            parallelism: 1,
+        }
+    }
+
+    /// the most a vault can ask for. without a cap, a tampered file could make unlocking take hours
+    /// or more memory than the machine has, and an upgrade could do the same by mistake
+    pub const MAX_MEMORY_COST: u32 = 4 * 1024 * 1024;
+    pub const MAX_TIME_COST: u32 = 64;
+    pub const MAX_PARALLELISM: u32 = 16;
+
+    /// refuse parameters over the caps, before anything is allocated for them
+    pub fn check_limits(&self) -> Result<(), String> {
+        if self.memory_cost > Self::MAX_MEMORY_COST
+            || self.time_cost > Self::MAX_TIME_COST
+            || self.parallelism > Self::MAX_PARALLELISM
+        {
+            return Err(format!(
+                "key derivation parameters are too expensive. the limits are {} MiB of memory, {} passes and {} lanes",
+                Self::MAX_MEMORY_COST / 1024,
+                Self::MAX_TIME_COST,
+                Self::MAX_PARALLELISM
+            ));
+        }
+        Ok(())
+    }
+
+    pub fn argon2(&self) -> Result<Argon2<'static>, String> {
+        self.check_limits()?;
+        let algorithm = Algorithm::from_str(&self.algorithm).map_err(|e| e.to_string())?;
+        let version = Version::try_from(self.version).map_err(|e| e.to_string())?;
+        let params = Params::new(self.memory_cost, self.time_cost, self.parallelism, Some(32))
+            .map_err(|e| e.to_string())?;
+        Ok(Argon2::new(algorithm, version, params))
+    }
+
+    /// `true` if `self` is stronger than `other`: the same algorithm, a version at least as new, at
+    /// least as expensive in every dimension, and not the same parameters
+    pub fn stronger_than(&self, other: &KdfParams) -> bool {
+        let algorithm = |params: &KdfParams| Algorithm::from_str(&params.algorithm).ok();
+        algorithm(self).is_some_and(|own| Some(own) == algorithm(other))
+            && self.version >= other.version
+            && self.memory_cost >= other.memory_cost
+            && self.time_cost >= other.time_cost
+            && self.parallelism >= other.parallelism
+            && self != other
+    }
+}
+
+impl Default for KdfParams {
+    /// parameters for newly created vaults
+    fn default() -> Self {
+        KdfParams::legacy()
+    }
+}
+
+/// when an unlocked vault locks itself again. `None` turns a limit off.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, specta::Type)]
+pub struct LockSettings {
+    /// lock after this many seconds without a command touching the vault
+    pub idle_timeout_secs: Option<u32>,
+    /// lock this many seconds after unlocking, however active the vault is
+    pub max_session_secs: Option<u32>,
+}
+
+impl Default for LockSettings {
+    fn default() -> Self {
+        LockSettings {
+            idle_timeout_secs: Some(15 * 60),
+            max_session_secs: None,
+        }
+    }
 }
 
-/// derive a 256-bit key from a password and salt, using argon2.
+/// the frontend passes timestamps as seconds since the unix epoch
+pub fn unix_time(secs: u64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+/// derive a 256-bit key from a password and salt, using argon2 with the given parameters.
 pub fn derive_key(
     password: &SecretString,
     salt: &[u8],
+    params: &KdfParams,
 ) -> Result<(LockGuard, SecretBox<[u8; 32]>), String> {
-    let argon2 = Argon2::default();
-    let key = alloc(32, Protection::READ_WRITE).map_err(|e| e.to_string())?;
-    let _guard = lock(key.as_ptr::<u8>(), 32).map_err(|e| e.to_string())?;
-    let key_slice = unsafe { &mut *(key.as_ptr::<u8>() as *mut [u8; 32]) };
+    let argon2 = params.argon2()?;
+    // lock the memory the key lives in for as long as it's in use, rather than a copy of it
+    let mut key = SecretBox::new(Box::new([0u8; 32]));
+    let _guard = lock(key.expose_secret().as_ptr(), 32).map_err(|e| e.to_string())?;
     argon2
-        .hash_password_into(password.expose_secret().as_bytes(), salt, key_slice)
-        .expect("failed to hash password into key");
+        .hash_password_into(
+            password.expose_secret().as_bytes(),
+            salt,
+            key.expose_secret_mut(),
+        )
+        .map_err(|e| format!("failed to hash password into key: {e}"))?;
 
-    Ok((_guard, SecretBox::new(Box::new(*key_slice))))
+    Ok((_guard, key))
 }
 
 /// an abstraction for the contents of the vault file. contains the `salt`, a `hello` value used to validate passwords, and a map of `secrets`.
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct VaultFile {
     salt: Vec<u8>,
+    kdf: KdfParams,
+    lock: LockSettings,
     hello: EncryptedSecret,
     secrets: BTreeMap<String, KeyMetadata>,
-    hmac: Option<Vec<u8>>,
+    /// `None` for vaults last saved before format version 4
+    entry_macs: Option<integrity::EntryMacs>,
+}
+
+/// the HMAC a vault file was stored with, along with the exact bytes it covers. checked on unlock.
+#[derive(Debug, Clone)]
+pub enum StoredMac {
+    /// format version 2 onwards. covers the format version and the whole encoded `VaultFile`, keyed
+    /// with a MAC key derived from the vault key.
+    FullFile {
+        version: u32,
+        body: Vec<u8>,
+        hmac: Vec<u8>,
+    },
+    /// before version 2. covers only `secrets`, keyed with the vault key itself. replaced with
+    /// `FullFile` the next time the vault is saved.
+    SecretsOnly {
+        secrets: Vec<u8>,
+        hmac: Option<Vec<u8>>,
+    },
 }
 
 pub struct Vault {
@@ -136,26 +367,65 @@ pub struct Vault {
     path: PathBuf,
     key: Option<SecretBox<[u8; 32]>>,
     _key_guard: Option<LockGuard>,
+    /// `None` until a vault created in this session is first saved
+    stored_mac: Option<StoredMac>,
+    /// set if the vault failed its integrity check on unlock
+    read_only: Option<IntegrityReport>,
+    attempts: throttle::UnlockAttempts,
+    /// the file as it was before being migrated on load, written out on the first save
+    migration_backup: Option<migrations::PendingBackup>,
 }
 
 type HmacSha256 = Hmac<Sha256>;
+/// a key from `derive_key`, with the guard keeping its memory locked
+type DerivedKey = (LockGuard, SecretBox<[u8; 32]>);
+
+/// derive a key used to authenticate the vault file, so the encryption key is never reused for a MAC
+fn mac_key(key: &SecretBox<[u8; 32]>, label: &[u8]) -> Zeroizing<[u8; 32]> {
+    let mut mac_key = Zeroizing::new([0u8; 32]);
+    Hkdf::<Sha256>::new(None, key.expose_secret())
+        .expand(label, &mut mac_key[..])
+        .expect("32 bytes is a valid hkdf output length");
+    mac_key
+}
+
+fn full_file_mac(key: &SecretBox<[u8; 32]>, version: u32) -> HmacSha256 {
+    let mut mac = <HmacSha256 as Mac>::new_from_slice(&mac_key(key, b"chiffrage vault hmac")[..])
+        .expect("hmac accepts any key length");
+    mac.update(&version.to_be_bytes());
+    mac
+}
 
 impl Vault {
     pub fn set_vault_key(&mut self, password: SecretString) -> Result<(), String> {
-        let (_guard, key) = derive_key(&password, &self.file.salt)?;
+        self.check_lockout()?;
+        let opened = self.open_key(&password)?;
+        self.record_unlock_attempt(opened.is_some());
+        let (guard, key) = opened.ok_or("password is incorrect".to_string())?;
+        self.install_key(guard, key)
+    }
+
+    /// derive the key from `password` and check it against the vault, without counting the attempt.
+    /// `None` if the password is wrong
+    fn open_key(&self, password: &SecretString) -> Result<Option<DerivedKey>, String> {
+        let (_guard, key) = derive_key(password, &self.file.salt, &self.file.kdf)?;
         let hello = &self.file.hello;
         let mut cipher = XChaCha20Poly1305::new(key.expose_secret().into());
         let nonce = XNonce::from_slice(hello.nonce.as_slice());
 
         let decrypted_bytes = cipher.decrypt(nonce, hello.ciphertext.as_ref());
-        if decrypted_bytes.is_err() {
-            return Err("password is incorrect".to_string());
-        };
+        Ok(decrypted_bytes.is_ok().then_some((_guard, key)))
+    }
+
+    /// unlock with a key from `open_key`, as long as the vault passes its integrity check
+    fn install_key(&mut self, guard: LockGuard, key: SecretBox<[u8; 32]>) -> Result<(), String> {
         self.key = Some(key);
-        self._key_guard = Some(_guard);
+        self._key_guard = Some(guard);
         if !self.verify_integrity() {
+            self.read_only = Some(self.integrity_report()?);
             return Err("integrity check failed".to_string());
         };
+        self.read_only = None;
         Ok(())
     }
     pub fn get_vault_key(&self) -> Result<&SecretBox<[u8; 32]>, String> {
@@ -164,6 +434,7 @@ impl Vault {
     pub fn delete_vault_key(&mut self) {
         self.key = None;
         self._key_guard = None;
+        self.read_only = None;
     }
     pub fn new_key(
         &self,
@@ -171,19 +442,23 @@ impl Vault {
         public: String,
         private: Option<SecretString>,
     ) -> Result<KeyMetadata, String> {
+        let id = create_id();
         let private = match private {
-            Some(private_key) => Some(Vault::encrypt_secret(self.get_vault_key()?, private_key)?),
+            Some(private_key) => Some(self.seal_private_key(&id, private_key)?),
             None => None,
         };
-        Ok(KeyMetadata::from_keypair(name, KeyPair { public, private }))
+        Ok(KeyMetadata::from_keypair(
+            id,
+            name,
+            KeyPair { public, private },
+        ))
     }
 
-    pub fn keypair_from(&self, identity: WildcardIdentity) -> Result<KeyPair, String> {
+    pub fn keypair_from(&self, id: &str, identity: WildcardIdentity) -> Result<KeyPair, String> {
         let identity_text = identity
             .to_string()
             .expect("string conversion should not fail");
-        let private = Vault::encrypt_secret(self.get_vault_key()?, identity_text)
-            .expect("encryption should not fail if vault key is set");
+        let private = self.seal_private_key(id, identity_text)?;
 
         Ok(KeyPair {
             public: identity.to_public()?.to_string()?,
@@ -191,56 +466,69 @@ impl Vault {
         })
     }
 
-    pub fn delete_key(&mut self, id: String) {
+    pub fn delete_key(&mut self, id: String) -> Result<(), String> {
+        self.ensure_writable()?;
         let _ = self.file.secrets.remove(&id);
+        Ok(())
     }
 
     /// generate an mlkem768x25519 identity
     pub fn generate_keypair(&self, name: String) -> Result<KeyMetadata, String> {
         let identity = HybridIdentity::generate();
+        let id = create_id();
         let keypair = KeyPair {
             public: identity.to_public().to_string(),
-            private: Some(Vault::encrypt_secret(
-                self.get_vault_key()?,
-                identity.to_string(),
-            )?),
+            private: Some(self.seal_private_key(&id, identity.to_string())?),
         };
-        Ok(KeyMetadata {
-            id: create_id(),
-            name,
-            key_type: KeyType::Private,
-            date_created: SystemTime::now(),
-            contents: keypair,
-        })
+        Ok(KeyMetadata::from_keypair(id, name, keypair))
     }
 
     /// generate an x25519 identity
     pub fn generate_x25519_keypair(&self, name: String) -> Result<KeyMetadata, String> {
         let identity = Identity::generate();
+        let id = create_id();
         let keypair = KeyPair {
             public: identity.to_public().to_string(),
-            private: Some(Vault::encrypt_secret(
-                self.get_vault_key()?,
-                SecretString::from(identity.to_string()),
-            )?),
+            private: Some(self.seal_private_key(&id, SecretString::from(identity.to_string()))?),
         };
-        Ok(KeyMetadata {
-            id: create_id(),
-            name,
-            key_type: KeyType::Private,
-            date_created: SystemTime::now(),
-            contents: keypair,
-        })
+        Ok(KeyMetadata::from_keypair(id, name, keypair))
+    }
+
+    /// decrypt the private key of `key`, checking that it was sealed for this entry
+    pub fn decrypt_private_key(&self, key: &KeyMetadata) -> Result<SecretString, String> {
+        let private = key
+            .contents
+            .private
+            .as_ref()
+            .ok_or("no private key!".to_string())?;
+        self.decrypt_secret(private, &secret_aad(&key.id, &key.key_type))
+    }
+
+    /// encrypt a private key for the entry `id`
+    fn seal_private_key(&self, id: &str, secret: SecretString) -> Result<EncryptedSecret, String> {
+        Vault::encrypt_secret(
+            self.get_vault_key()?,
+            secret,
+            Some(&secret_aad(id, &KeyType::Private)),
+        )
     }
 
-    pub fn decrypt_secret(
+    /// `aad` is only checked if the secret is `bound`
+    fn decrypt_secret(
         &self,
         encrypted_secret: &EncryptedSecret,
+        aad: &[u8],
     ) -> Result<SecretString, String> {
         let mut cipher = XChaCha20Poly1305::new(self.get_vault_key()?.expose_secret().into());
         let nonce = XNonce::from_slice(&encrypted_secret.nonce);
 
-        let decrypted_bytes = cipher.decrypt(nonce, encrypted_secret.ciphertext.as_ref());
+        let decrypted_bytes = cipher.decrypt(
+            nonce,
+            Payload {
+                msg: &encrypted_secret.ciphertext,
+                aad: if encrypted_secret.bound { aad } else { &[] },
+            },
+        );
         if decrypted_bytes.is_err() {
             return Err("failed to decrypt secret.".to_string());
         }
@@ -250,9 +538,11 @@ impl Vault {
         ));
     }
 
+    /// the result is `bound` if `aad` is given
     fn encrypt_secret(
         key: &SecretBox<[u8; 32]>,
         secret: SecretString,
+        aad: Option<&[u8]>,
     ) -> Result<EncryptedSecret, String> {
         let key_ptr = alloc(32, Protection::READ_WRITE).map_err(|e| e.to_string())?;
         let _guard = lock(key_ptr.as_ptr::<u8>(), 32);
@@ -268,12 +558,19 @@ impl Vault {
         drop(_guard);
 
         let ciphertext = cipher
-            .encrypt(&nonce, secret.expose_secret().as_bytes())
+            .encrypt(
+                &nonce,
+                Payload {
+                    msg: secret.expose_secret().as_bytes(),
+                    aad: aad.unwrap_or_default(),
+                },
+            )
             .map_err(|e| e.to_string())?;
 
         Ok(EncryptedSecret {
             nonce: nonce.to_vec(),
             ciphertext,
+            bound: aad.is_some(),
         })
     }
 
@@ -282,80 +579,481 @@ impl Vault {
     }
 
     pub fn put_key(&mut self, key: KeyMetadata) -> Result<(), String> {
+        self.ensure_writable()?;
         self.file.secrets.insert(key.id.clone(), key);
         Ok(())
     }
 
-    pub fn load_vault(path: &str) -> Result<Self, String> {
+    pub fn load_vault(path: &str) -> Result<Self, VaultLoadError> {
         if !std::path::Path::new(path).exists() {
-            return Err("vault does not exist".to_string());
+            return Err(VaultLoadError::Missing);
         }
-        let data = fs::read(path).expect("could not read vault");
-        let vault_file: VaultFile = serde_cbor::from_slice(&data).expect("could not parse vault");
+        let path = PathBuf::from_str(path).expect("invalid path");
+        let data = fs::read(&path).map_err(|e| VaultLoadError::Unreadable(e.to_string()))?;
+        let (vault_file, stored_mac, attempts, migration_backup) = migrations::deserialize(&data)?;
 
         let vault = Vault {
             file: vault_file,
-            path: PathBuf::from_str(path).expect("invalid path"),
+            path,
             key: None,
             _key_guard: None,
+            stored_mac: Some(stored_mac),
+            read_only: None,
+            attempts,
+            migration_backup,
         };
 
         Ok(vault)
     }
 
+    /// open backup `generation` of the vault at `path`, check it against `password` and its HMAC,
+    /// and save it as the current vault. the vault it replaces becomes backup 1.
+    ///
+    /// wrong passwords count against `live`, the vault loaded from `path`, so the backups don't each
+    /// get their own attempts on top of the vault's. if it couldn't be loaded, they count against
+    /// the newest backup that can be, which all restores then share.
+    pub fn restore_backup(
+        path: &Path,
+        generation: u32,
+        password: SecretString,
+        live: Option<&mut Vault>,
+    ) -> Result<Vault, String> {
+        let load = |generation| {
+            let backup = backups::backup_path(path, generation);
+            Vault::load_vault(backup.to_str().ok_or("invalid path".to_string())?)
+                .map_err(|e| e.to_string())
+        };
+        let mut vault = load(generation)?;
+        let mut newest;
+        let counter = match live {
+            Some(live) => live,
+            None => {
+                newest = backups::list_backups(path)
+                    .iter()
+                    .find_map(|backup| load(backup.generation).ok())
+                    .ok_or("no backup can be loaded".to_string())?;
+                &mut newest
+            }
+        };
+        counter.check_lockout()?;
+        let opened = vault.open_key(&password)?;
+        counter.record_unlock_attempt(opened.is_some());
+        let (guard, key) = opened.ok_or("password is incorrect".to_string())?;
+        vault.install_key(guard, key)?;
+        vault.attempts = throttle::UnlockAttempts::default();
+        vault.path = path.to_path_buf();
+        vault.save_vault()?;
+        Ok(vault)
+    }
+
+    /// copy the vault file at `source` over the vault at `path`, as long as it loads
+    pub fn replace_with(path: &Path, source: &str) -> Result<Vault, String> {
+        let mut vault = Vault::load_vault(source)?;
+        // copy the file as-is, since it can't be re-encoded (and re-authenticated) until it's unlocked
+        let data = fs::read(source).map_err(|e| e.to_string())?;
+        backups::write_atomic(path, &data)?;
+        vault.path = path.to_path_buf();
+        Ok(vault)
+    }
+
+    /// rename the vault at `path` to `<name>.damaged-<unix time>`
+    pub fn set_aside(path: &Path) -> Result<(), String> {
+        if !path.exists() {
+            return Ok(());
+        }
+        let timestamp = SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_err(|e| e.to_string())?
+            .as_secs();
+        let mut name = path.file_name().unwrap_or_default().to_os_string();
+        name.push(format!(".damaged-{timestamp}"));
+        fs::rename(path, path.with_file_name(name))
+            .map_err(|e| format!("failed to move vault: {e}"))
+    }
+
+    /// delete the vault at `path` along with its backups
+    pub fn delete_files(path: &Path) -> Result<(), String> {
+        for generation in 1..=backups::BACKUP_COUNT {
+            let _ = fs::remove_file(backups::backup_path(path, generation));
+        }
+        if path.exists() {
+            fs::remove_file(path).map_err(|e| format!("failed to delete vault: {e}"))?;
+        }
+        Ok(())
+    }
+
+    pub fn list_backups(path: &Path) -> Vec<VaultBackup> {
+        backups::list_backups(path)
+    }
+
     pub fn create_vault(path: &str, password: &SecretString) -> Result<Vault, String> {
         let mut salt = [0u8; 16];
         OsRng.fill_bytes(&mut salt);
 
-        let (_guard, key) = derive_key(password, &salt)?;
+        let kdf = KdfParams::default();
+        let (_guard, key) = derive_key(password, &salt, &kdf)?;
 
         let vault_file = VaultFile {
             salt: salt.to_vec(),
-            hello: Vault::encrypt_secret(&key, SecretString::from("hello"))?,
+            kdf,
+            lock: LockSettings::default(),
+            hello: Vault::encrypt_secret(&key, SecretString::from("hello"), None)?,
             secrets: BTreeMap::new(),
-            hmac: None,
+            entry_macs: None,
         };
         Ok(Vault {
             file: vault_file,
             path: PathBuf::from_str(path).expect("invalid path"),
             key: Some(key),
             _key_guard: Some(_guard),
+            stored_mac: None,
+            read_only: None,
+            attempts: throttle::UnlockAttempts::default(),
+            migration_backup: None,
         })
     }
 
+    /// check the HMAC the vault was loaded with against the bytes it was loaded from
     pub fn verify_integrity(&self) -> bool {
-        let calculated_mac = self.vault_hmac();
-        return self
+        let Some(key) = self.key.as_ref() else {
+            return false;
+        };
+        match &self.stored_mac {
+            Some(StoredMac::FullFile {
+                version,
+                body,
+                hmac,
+            }) => {
+                let mut mac = full_file_mac(key, *version);
+                mac.update(body);
+                mac.verify_slice(hmac).is_ok()
+            }
+            Some(StoredMac::SecretsOnly {
+                secrets,
+                hmac: Some(hmac),
+            }) => {
+                let mut mac = <HmacSha256 as Mac>::new_from_slice(&key.expose_secret()[..])
+                    .expect("hmac accepts any key length");
+                mac.update(secrets);
+                mac.verify_slice(hmac).is_ok()
+            }
+            _ => false,
+        }
+    }
+
+    /// `true` if the vault is still authenticated with the pre-version 2 secrets-only HMAC
+    pub fn needs_mac_upgrade(&self) -> bool {
+        matches!(self.stored_mac, Some(StoredMac::SecretsOnly { .. }))
+    }
+
+    /// re-encrypt the vault under a new password. the old password is checked first.
+    pub fn change_password(
+        &mut self,
+        old_password: SecretString,
+        new_password: SecretString,
+    ) -> Result<(), String> {
+        self.with_password(old_password, |vault| {
+            let kdf = vault.file.kdf.clone();
+            vault.rekey(&new_password, kdf)
+        })
+    }
+
+    /// re-derive the vault key with stronger argon2 parameters. the password is checked first.
+    pub fn upgrade_kdf(&mut self, password: SecretString, kdf: KdfParams) -> Result<(), String> {
+        if !kdf.stronger_than(&self.file.kdf) {
+            return Err(
+                "new key derivation parameters must be stronger than the current ones".to_string(),
+            );
+        }
+        kdf.argon2()?;
+        self.with_password(password.clone(), |vault| vault.rekey(&password, kdf))
+    }
+
+    /// check `password` by unlocking with it, and run `f` on the unlocked vault. a vault that was
+    /// locked is locked again afterwards, since there's no session keeping track of it
+    fn with_password(
+        &mut self,
+        password: SecretString,
+        f: impl FnOnce(&mut Self) -> Result<(), String>,
+    ) -> Result<(), String> {
+        let was_locked = self.key.is_none();
+        let result = self.set_vault_key(password).and_then(|_| f(self));
+        if was_locked {
+            self.delete_vault_key();
+        }
+        result
+    }
+
+    pub fn kdf_params(&self) -> &KdfParams {
+        &self.file.kdf
+    }
+
+    pub fn lock_settings(&self) -> &LockSettings {
+        &self.file.lock
+    }
+
+    pub fn set_lock_settings(&mut self, settings: LockSettings) -> Result<(), String> {
+        let old = std::mem::replace(&mut self.file.lock, settings);
+        let result = self.save_vault();
+        if result.is_err() {
+            self.file.lock = old;
+        }
+        result
+    }
+
+    /// derive a new key from `password` with a fresh salt and re-encrypt every secret under it.
+    ///
+    /// everything is re-encrypted into a new `VaultFile` before anything is swapped in, and if
+    /// saving fails the old file and key are put back. once it's saved, every backup is deleted:
+    /// they're still sealed with the old key, so restoring one would undo the change.
+    fn rekey(&mut self, password: &SecretString, kdf: KdfParams) -> Result<(), String> {
+        let mut salt = [0u8; 16];
+        OsRng.fill_bytes(&mut salt);
+        let (new_guard, new_key) = derive_key(password, &salt, &kdf)?;
+
+        let mut secrets = BTreeMap::new();
+        for (id, key) in self.file.secrets.iter() {
+            let mut key = key.clone();
+            if key.contents.private.is_some() {
+                let secret = self.decrypt_private_key(&key)?;
+                let aad = secret_aad(&key.id, &key.key_type);
+                key.contents.private = Some(Vault::encrypt_secret(&new_key, secret, Some(&aad))?);
+            }
+            secrets.insert(id.clone(), key);
+        }
+        let new_file = VaultFile {
+            salt: salt.to_vec(),
+            kdf,
+            lock: self.file.lock.clone(),
+            hello: Vault::encrypt_secret(&new_key, SecretString::from("hello"), None)?,
+            secrets,
+            entry_macs: None,
+        };
+
+        let old_file = std::mem::replace(&mut self.file, new_file);
+        let old_key = self.key.replace(new_key);
+        let old_guard = self._key_guard.replace(new_guard);
+
+        if let Err(error) = self.save_vault() {
+            self.file = old_file;
+            self.key = old_key;
+            self._key_guard = old_guard;
+            return Err(error);
+        }
+        backups::delete_backups(&self.path)
+            .and(migrations::delete_backups(&self.path))
+            .map_err(|e| {
+                format!("the vault was re-encrypted, but {e}. delete its old backups by hand")
+            })
+    }
+
+    /// re-encrypt private keys that aren't bound to their entries yet. ones that can't be
+    /// decrypted are left alone rather than blocking the save.
+    fn reseal_legacy_secrets(&mut self) -> Result<(), String> {
+        let legacy_ids: Vec<String> = self
             .file
-            .hmac
-            .as_ref()
-            .is_some_and(|hmac| calculated_mac == *hmac);
+            .secrets
+            .values()
+            .filter(|key| {
+                key.contents
+                    .private
+                    .as_ref()
+                    .is_some_and(|private| !private.bound)
+            })
+            .map(|key| key.id.clone())
+            .collect();
+        for id in legacy_ids {
+            let key = &self.file.secrets[&id];
+            let Ok(secret) = self.decrypt_private_key(key) else {
+                continue;
+            };
+            let aad = secret_aad(&key.id, &key.key_type);
+            let sealed = Vault::encrypt_secret(self.get_vault_key()?, secret, Some(&aad))?;
+            if let Some(key) = self.file.secrets.get_mut(&id) {
+                key.contents.private = Some(sealed);
+            }
+        }
+        Ok(())
     }
 
-    fn vault_hmac(&self) -> Vec<u8> {
-        let secrets_bytes =
-            serde_cbor::to_vec(&self.file.secrets).expect("failed to serialize vault");
-        let mut mac =
-            <HmacSha256 as Mac>::new_from_slice(&self.key.as_ref().unwrap().expose_secret()[..])
-                .expect("key should be set");
-        mac.update(secrets_bytes.as_slice());
-        mac.finalize().into_bytes().to_vec()
+    /// re-derive the public half of every key from its private key. public-only keys, and private
+    /// keys that can't be decrypted, are left as they are.
+    pub fn regenerate_public_identities(&mut self) -> Result<(), String> {
+        self.ensure_writable()?;
+        let private_ids: Vec<String> = self
+            .file
+            .secrets
+            .values()
+            .filter(|key| key.contents.private.is_some())
+            .map(|key| key.id.clone())
+            .collect();
+        for id in private_ids {
+            let Ok(identity) = self
+                .decrypt_private_key(&self.file.secrets[&id])
+                .and_then(WildcardIdentity::from_secret)
+            else {
+                continue;
+            };
+            // plugin identities can't say what their recipient is, so theirs is kept as imported
+            if matches!(identity, WildcardIdentity::Plugin(_)) {
+                continue;
+            }
+            let keypair = self.keypair_from(&id, identity)?;
+            if let Some(key) = self.file.secrets.get_mut(&id) {
+                key.contents = keypair;
+            }
+        }
+        Ok(())
     }
+
     pub fn save_vault(&mut self) -> Result<(), String> {
+        self.ensure_writable()?;
         let path = self.path.clone();
         if let Some(parent) = self.path.parent() {
-            std::fs::create_dir_all(parent).expect("failed to create parent directories");
+            std::fs::create_dir_all(parent)
+                .map_err(|e| format!("failed to create parent directories: {e}"))?;
         }
 
-        if self.key.is_none() {
-            return Err("key is not set".to_string());
-        }
+        self.reseal_legacy_secrets()?;
+        self.file.entry_macs = Some(self.compute_entry_macs()?);
+        let key = self.key.as_ref().ok_or("key is not set".to_string())?;
 
-        self.file.hmac = Some(self.vault_hmac());
+        let body = migrations::encode_body(&self.file)?;
+        let mut mac = full_file_mac(key, migrations::CURRENT_VERSION);
+        mac.update(&body);
+        let hmac = mac.finalize().into_bytes().to_vec();
 
-        let data = serde_cbor::to_vec(&self.file).expect("failed to serialize vault");
-        std::fs::write(path, &data).expect("failed to init vault file");
+        let data = migrations::encode_envelope(
+            migrations::CURRENT_VERSION,
+            body.clone(),
+            hmac.clone(),
+            &self.attempts,
+        )?;
+        if let Some(backup) = &self.migration_backup {
+            backup.write(&path)?;
+        }
+        backups::write_atomic(&path, &data)?;
+        self.migration_backup = None;
+        self.stored_mac = Some(StoredMac::FullFile {
+            version: migrations::CURRENT_VERSION,
+            body,
+            hmac,
+        });
 
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PASSWORD: &str = "correct horse battery staple";
+
+    /// a new vault in `dir`, saved and still unlocked
+    fn saved_vault(dir: &Path) -> Vault {
+        let path = dir.join("vault.cb");
+        let mut vault =
+            Vault::create_vault(path.to_str().unwrap(), &SecretString::from(PASSWORD)).unwrap();
+        vault.save_vault().unwrap();
+        vault
+    }
+
+    fn reload(vault: &Vault) -> Vault {
+        Vault::load_vault(vault.path.to_str().unwrap()).unwrap()
+    }
+
+    /// a private key entry added to `vault`. returns its id
+    fn add_key(vault: &mut Vault, name: &str) -> String {
+        let key = vault.generate_x25519_keypair(name.to_string()).unwrap();
+        let id = key.id.clone();
+        vault.put_key(key).unwrap();
+        id
+    }
+
+    #[test]
+    fn rejects_a_tampered_envelope() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut vault = saved_vault(dir.path());
+        add_key(&mut vault, "laptop");
+        vault.save_vault().unwrap();
+
+        let Some(StoredMac::FullFile { body, hmac, .. }) = vault.stored_mac.clone() else {
+            panic!("a saved vault has a full file HMAC");
+        };
+        let mut file: VaultFile = serde_cbor::from_slice(&body).unwrap();
+        file.lock.idle_timeout_secs = None;
+        let changed_body = migrations::encode_body(&file).unwrap();
+        let changed_version = migrations::CURRENT_VERSION - 1;
+        for (version, body) in [
+            (migrations::CURRENT_VERSION, changed_body),
+            (changed_version, body),
+        ] {
+            let data = migrations::encode_envelope(
+                version,
+                body,
+                hmac.clone(),
+                &throttle::UnlockAttempts::default(),
+            )
+            .unwrap();
+            fs::write(&vault.path, data).unwrap();
+            let mut tampered = reload(&vault);
+            assert_eq!(
+                tampered.set_vault_key(SecretString::from(PASSWORD)),
+                Err("integrity check failed".to_string())
+            );
+            assert!(tampered.ensure_writable().is_err());
+        }
+    }
+
+    #[test]
+    fn private_keys_only_decrypt_in_their_own_entry() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut vault = saved_vault(dir.path());
+        let laptop = add_key(&mut vault, "laptop");
+        let phone = add_key(&mut vault, "phone");
+
+        let mut swapped = vault.file.secrets[&laptop].clone();
+        swapped.contents = vault.file.secrets[&phone].contents.clone();
+        assert!(vault.decrypt_private_key(&swapped).is_err());
+
+        let mut retyped = vault.file.secrets[&laptop].clone();
+        retyped.key_type = KeyType::Public;
+        assert!(vault.decrypt_private_key(&retyped).is_err());
+
+        assert!(vault
+            .decrypt_private_key(&vault.file.secrets[&laptop])
+            .is_ok());
+    }
+
+    #[test]
+    fn reseals_unbound_private_keys() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut vault = saved_vault(dir.path());
+        let laptop = add_key(&mut vault, "laptop");
+        let phone = add_key(&mut vault, "phone");
+        let secret = vault
+            .decrypt_private_key(&vault.file.secrets[&laptop])
+            .unwrap();
+        let unbound =
+            Vault::encrypt_secret(vault.get_vault_key().unwrap(), secret.clone(), None).unwrap();
+        assert!(!unbound.bound);
+        let entry = vault.file.secrets.get_mut(&laptop).unwrap();
+        entry.contents.private = Some(unbound.clone());
+
+        // an unbound secret still decrypts anywhere, which is what resealing fixes
+        let mut moved = vault.file.secrets[&phone].clone();
+        moved.contents.private = Some(unbound);
+        assert!(vault.decrypt_private_key(&moved).is_ok());
+
+        vault.reseal_legacy_secrets().unwrap();
+        let key = &vault.file.secrets[&laptop];
+        assert!(key.contents.private.as_ref().unwrap().bound);
+        assert_eq!(
+            vault.decrypt_private_key(key).unwrap().expose_secret(),
+            secret.expose_secret()
+        );
+        moved.contents.private = key.contents.private.clone();
+        assert!(vault.decrypt_private_key(&moved).is_err());
+    }
+}
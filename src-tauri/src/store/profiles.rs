@@ -0,0 +1,113 @@
+// the list of vaults the app knows about, and which one is open.
+// kept in `vaults.json` in the app data directory. only names and paths are stored there, so each
+// vault keeps its own password and can live anywhere, e.g. on a removable drive.
+use cuid2::create_id;
+use serde::{Deserialize, Serialize};
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use super::backups;
+
+const PROFILES_FILE: &str = "vaults.json";
+pub const DEFAULT_VAULT: &str = "default";
+
+#[derive(Serialize, Deserialize, Debug, Clone, specta::Type)]
+pub struct VaultProfile {
+    pub name: String,
+    pub path: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, specta::Type)]
+pub struct VaultProfiles {
+    /// the name of the open vault
+    pub active: String,
+    pub vaults: Vec<VaultProfile>,
+}
+
+impl VaultProfiles {
+    /// just the vault every install had before profiles existed
+    pub fn initial(data_dir: &Path) -> VaultProfiles {
+        VaultProfiles {
+            active: DEFAULT_VAULT.to_string(),
+            vaults: vec![VaultProfile {
+                name: DEFAULT_VAULT.to_string(),
+                path: data_dir.join("vault.cb").to_string_lossy().to_string(),
+            }],
+        }
+    }
+
+    pub fn load(data_dir: &Path) -> Result<VaultProfiles, String> {
+        let path = data_dir.join(PROFILES_FILE);
+        if !path.exists() {
+            return Ok(VaultProfiles::initial(data_dir));
+        }
+        let data = fs::read(&path).map_err(|e| format!("failed to read vault list: {e}"))?;
+        let profiles: VaultProfiles =
+            serde_json::from_slice(&data).map_err(|e| format!("vault list is damaged: {e}"))?;
+        profiles.get(&profiles.active)?;
+        Ok(profiles)
+    }
+
+    pub fn save(&self, data_dir: &Path) -> Result<(), String> {
+        let data = serde_json::to_vec_pretty(self).map_err(|e| e.to_string())?;
+        backups::overwrite_atomic(&data_dir.join(PROFILES_FILE), &data)
+    }
+
+    pub fn get(&self, name: &str) -> Result<&VaultProfile, String> {
+        self.vaults
+            .iter()
+            .find(|profile| profile.name == name)
+            .ok_or(format!("there is no vault called \"{name}\""))
+    }
+
+    pub fn active(&self) -> &VaultProfile {
+        self.get(&self.active)
+            .expect("the active vault should be in the list")
+    }
+
+    pub fn active_path(&self) -> PathBuf {
+        PathBuf::from(&self.active().path)
+    }
+
+    /// register a vault. without a `path`, a new file is picked in `data_dir`
+    pub fn add(
+        &mut self,
+        name: String,
+        path: Option<String>,
+        data_dir: &Path,
+    ) -> Result<VaultProfile, String> {
+        let name = name.trim().to_string();
+        if name.is_empty() {
+            return Err("no name set".to_string());
+        }
+        if self.get(&name).is_ok() {
+            return Err(format!("there is already a vault called \"{name}\""));
+        }
+        let path = match path {
+            Some(path) => path,
+            None => data_dir
+                .join("vaults")
+                .join(format!("{}.cb", create_id()))
+                .to_string_lossy()
+                .to_string(),
+        };
+        if self.vaults.iter().any(|profile| profile.path == path) {
+            return Err("that vault is already in the list".to_string());
+        }
+        let profile = VaultProfile { name, path };
+        self.vaults.push(profile.clone());
+        Ok(profile)
+    }
+
+    pub fn remove(&mut self, name: &str) -> Result<VaultProfile, String> {
+        if name == self.active {
+            return Err("switch to another vault before removing this one".to_string());
+        }
+        let index = self
+            .vaults
+            .iter()
+            .position(|profile| profile.name == name)
+            .ok_or(format!("there is no vault called \"{name}\""))?;
+        Ok(self.vaults.remove(index))
+    }
+}
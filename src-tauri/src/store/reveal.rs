@@ -0,0 +1,46 @@
+// showing or exporting a private key. the commands that do this need the vault password to have
+// been entered again just before (see `session::take_reauthentication`), and every reveal is
+// recorded on the key so there's a history of when it left the vault.
+use secrecy::SecretString;
+use serde::{Deserialize, Serialize};
+use std::time::SystemTime;
+
+use super::Vault;
+
+#[derive(Serialize, Deserialize, Debug, Clone, specta::Type)]
+pub enum RevealKind {
+    /// shown in the app
+    Shown,
+    /// written to a file
+    Exported,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, specta::Type)]
+pub struct KeyReveal {
+    pub at: SystemTime,
+    pub kind: RevealKind,
+}
+
+impl Vault {
+    /// decrypt the private key `id` and record that it was revealed. the vault has to be saved
+    /// afterwards for the record to stick
+    pub fn reveal_private_key(
+        &mut self,
+        id: &str,
+        kind: RevealKind,
+    ) -> Result<SecretString, String> {
+        self.ensure_writable()?;
+        let key = self.get_key(id).ok_or("key does not exist".to_string())?;
+        let private = self.decrypt_private_key(key)?;
+        let key = self
+            .file
+            .secrets
+            .get_mut(id)
+            .expect("the key was just found");
+        key.reveals.push(KeyReveal {
+            at: SystemTime::now(),
+            kind,
+        });
+        Ok(private)
+    }
+}
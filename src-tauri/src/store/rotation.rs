@@ -0,0 +1,58 @@
+// key rotation.
+// rotating a key generates a successor in the same format and retires the old key. a retired key
+// stays in the vault so files encrypted to it can still be decrypted, but it can't be encrypted to.
+use std::time::SystemTime;
+
+use super::{KeyMetadata, Vault};
+use crate::crypto::WildcardRecipient;
+
+impl Vault {
+    /// generate a successor for the private key `id` and retire `id`. the successor takes over the
+    /// name, email, notes and tags. returns the successor, redacted
+    pub fn rotate_key(
+        &mut self,
+        id: &str,
+        expires_at: Option<SystemTime>,
+    ) -> Result<KeyMetadata, String> {
+        self.ensure_writable()?;
+        let old = self
+            .get_key(id)
+            .ok_or("key does not exist".to_string())?
+            .clone();
+        if old.contents.private.is_none() {
+            return Err("only your own keys can be rotated".to_string());
+        }
+        if matches!(
+            WildcardRecipient::from_public(&old.contents.public),
+            Ok(WildcardRecipient::Ssh(_) | WildcardRecipient::Plugin(_))
+        ) {
+            return Err(
+                "ssh and plugin keys can't be rotated here. import the new key instead".to_string(),
+            );
+        }
+        if old.retired_at.is_some() {
+            return Err("this key has already been rotated".to_string());
+        }
+
+        let mut successor = if old.contents.public.starts_with("age1pq") {
+            self.generate_keypair(old.name.clone())
+        } else {
+            self.generate_x25519_keypair(old.name.clone())
+        }?;
+        successor.email = old.email.clone();
+        successor.notes = old.notes.clone();
+        successor.tags = old.tags.clone();
+        successor.expires_at = expires_at;
+        successor.predecessor = Some(old.id.clone());
+
+        let mut retired = old;
+        let now = SystemTime::now();
+        retired.retired_at = Some(now);
+        retired.date_modified = now;
+        retired.successor = Some(successor.id.clone());
+
+        self.put_key(retired)?;
+        self.put_key(successor.clone())?;
+        Ok(successor.redacted())
+    }
+}
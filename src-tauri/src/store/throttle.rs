@@ -0,0 +1,174 @@
+// backoff for failed unlock attempts.
+// after `FREE_ATTEMPTS` wrong passwords in a row, each further attempt has to wait twice as long as
+// the last one, up to `MAX_LOCKOUT`. the counter is kept in the vault envelope, next to the HMAC
+// rather than under it, so it can be written while the vault is locked. this only slows down
+// guessing through the app; anyone who can edit the file can attack it offline anyway.
+use serde::{Deserialize, Serialize};
+use std::time::{Duration, SystemTime};
+
+use super::{backups, migrations, StoredMac, Vault};
+
+const FREE_ATTEMPTS: u32 = 3;
+const BASE_LOCKOUT: Duration = Duration::from_secs(2);
+const MAX_LOCKOUT: Duration = Duration::from_secs(15 * 60);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct UnlockAttempts {
+    /// wrong passwords since the last successful unlock
+    pub failures: u32,
+    /// unix time of the last wrong password, in seconds
+    pub last_failure: u64,
+}
+
+impl UnlockAttempts {
+    fn lockout(&self) -> Duration {
+        if self.failures < FREE_ATTEMPTS {
+            return Duration::ZERO;
+        }
+        let doublings = (self.failures - FREE_ATTEMPTS).min(31);
+        BASE_LOCKOUT.saturating_mul(1 << doublings).min(MAX_LOCKOUT)
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl Vault {
+    /// how long until the password can be tried again, if it can't be right now
+    pub fn lockout_remaining(&self) -> Option<Duration> {
+        let until = Duration::from_secs(self.attempts.last_failure) + self.attempts.lockout();
+        let remaining = until.saturating_sub(Duration::from_secs(unix_now()));
+        (!remaining.is_zero()).then_some(remaining)
+    }
+
+    pub(super) fn check_lockout(&self) -> Result<(), String> {
+        match self.lockout_remaining() {
+            Some(remaining) => Err(format!(
+                "too many failed attempts. try again in {} seconds",
+                remaining.as_secs().max(1)
+            )),
+            None => Ok(()),
+        }
+    }
+
+    pub(super) fn record_unlock_attempt(&mut self, success: bool) {
+        if success {
+            if self.attempts.failures == 0 {
+                return;
+            }
+            self.attempts = UnlockAttempts::default();
+        } else {
+            self.attempts.failures = self.attempts.failures.saturating_add(1);
+            self.attempts.last_failure = unix_now();
+        }
+        // best effort: failing to persist the counter shouldn't stop anyone unlocking their vault
+        let _ = self.write_attempts();
+    }
+
+    /// rewrite the envelope with the current counter, keeping the stored vault and its HMAC as they are.
+    /// vaults still in a pre-version 2 file only keep the counter in memory until they're next saved.
+    fn write_attempts(&self) -> Result<(), String> {
+        let Some(StoredMac::FullFile {
+            version,
+            body,
+            hmac,
+        }) = &self.stored_mac
+        else {
+            return Ok(());
+        };
+        let data =
+            migrations::encode_envelope(*version, body.clone(), hmac.clone(), &self.attempts)?;
+        backups::overwrite_atomic(&self.path, &data)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use secrecy::SecretString;
+
+    fn attempts(failures: u32) -> UnlockAttempts {
+        UnlockAttempts {
+            failures,
+            last_failure: unix_now(),
+        }
+    }
+
+    /// a new vault in `dir`, saved and then locked
+    fn locked_vault(dir: &std::path::Path) -> Vault {
+        let path = dir.join("vault.cb");
+        let mut vault =
+            Vault::create_vault(path.to_str().unwrap(), &SecretString::from("hunter2")).unwrap();
+        vault.save_vault().unwrap();
+        vault.delete_vault_key();
+        vault
+    }
+
+    #[test]
+    fn the_first_failures_are_free() {
+        for failures in 0..FREE_ATTEMPTS {
+            assert_eq!(attempts(failures).lockout(), Duration::ZERO);
+        }
+        assert_eq!(attempts(FREE_ATTEMPTS).lockout(), BASE_LOCKOUT);
+    }
+
+    #[test]
+    fn doubles_up_to_the_cap() {
+        assert_eq!(attempts(FREE_ATTEMPTS + 1).lockout(), BASE_LOCKOUT * 2);
+        assert_eq!(attempts(FREE_ATTEMPTS + 2).lockout(), BASE_LOCKOUT * 4);
+        assert_eq!(attempts(FREE_ATTEMPTS + 8).lockout(), BASE_LOCKOUT * 256);
+        assert_eq!(attempts(FREE_ATTEMPTS + 9).lockout(), MAX_LOCKOUT);
+        assert_eq!(attempts(u32::MAX).lockout(), MAX_LOCKOUT);
+    }
+
+    #[test]
+    fn the_counter_survives_a_reload() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut vault = locked_vault(dir.path());
+        for _ in 0..FREE_ATTEMPTS {
+            let result = vault.set_vault_key(SecretString::from("wrong"));
+            assert_eq!(result, Err("password is incorrect".to_string()));
+        }
+
+        let mut reloaded = Vault::load_vault(vault.path.to_str().unwrap()).unwrap();
+        assert_eq!(reloaded.attempts.failures, FREE_ATTEMPTS);
+        let result = reloaded.set_vault_key(SecretString::from("hunter2"));
+        assert!(result.unwrap_err().starts_with("too many failed attempts"));
+        assert!(reloaded.lockout_remaining().is_some());
+
+        // a successful unlock resets it, on disk too
+        reloaded.attempts.last_failure = 0;
+        let password = SecretString::from("hunter2");
+        reloaded.set_vault_key(password).unwrap();
+        let reloaded = Vault::load_vault(vault.path.to_str().unwrap()).unwrap();
+        assert_eq!(reloaded.attempts.failures, 0);
+    }
+
+    #[test]
+    fn restoring_a_backup_counts_against_the_live_vault() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut live = locked_vault(dir.path());
+        live.set_vault_key(SecretString::from("hunter2")).unwrap();
+        live.save_vault().unwrap();
+        live.delete_vault_key();
+        let backup = backups::backup_path(&live.path, 1);
+        let backup_before = std::fs::read(&backup).unwrap();
+
+        let path = live.path.clone();
+        let result = Vault::restore_backup(&path, 1, SecretString::from("wrong"), Some(&mut live));
+        assert_eq!(result.err(), Some("password is incorrect".to_string()));
+        assert_eq!(live.attempts.failures, 1);
+        assert_eq!(std::fs::read(&backup).unwrap(), backup_before);
+        let reloaded = Vault::load_vault(path.to_str().unwrap()).unwrap();
+        assert_eq!(reloaded.attempts.failures, 1);
+
+        live.attempts = attempts(FREE_ATTEMPTS);
+        let result =
+            Vault::restore_backup(&path, 1, SecretString::from("hunter2"), Some(&mut live));
+        assert!(result.is_err_and(|error| error.starts_with("too many failed attempts")));
+    }
+}
@@ -8,10 +8,13 @@ export const commands = {
 async isFirstOpen() : Promise<boolean> {
     return await TAURI_INVOKE("is_first_open");
 },
+async startupState() : Promise<StartupState> {
+    return await TAURI_INVOKE("startup_state");
+},
 async fetchKeys() : Promise<KeyMetadata[]> {
     return await TAURI_INVOKE("fetch_keys");
 },
-async loadVault() : Promise<Result<null, string>> {
+async loadVault() : Promise<Result<null, VaultLoadError>> {
     try {
     return { status: "ok", data: await TAURI_INVOKE("load_vault") };
 } catch (e) {
@@ -19,6 +22,29 @@ async loadVault() : Promise<Result<null, string>> {
     else return { status: "error", error: e  as any };
 }
 },
+/**
+ * replace the vault with a copy of the vault file at `path`. the file has to load, and the vault
+ * it replaces is kept as backup 1.
+ */
+async replaceVaultFile(path: string) : Promise<Result<null, string>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("replace_vault_file", { path }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+/**
+ * move the current vault file out of the way (it is renamed, never deleted) so a new one can be created
+ */
+async resetVault() : Promise<Result<null, string>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("reset_vault") };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
 async createVault(password: string) : Promise<Result<null, string>> {
     try {
     return { status: "ok", data: await TAURI_INVOKE("create_vault", { password }) };
@@ -27,28 +53,163 @@ async createVault(password: string) : Promise<Result<null, string>> {
     else return { status: "error", error: e  as any };
 }
 },
+async listVaults() : Promise<VaultProfiles> {
+    return await TAURI_INVOKE("list_vaults");
+},
+/**
+ * add a vault to the list. with a `path`, that file is used (and created if it doesn't exist yet).
+ * without one, a new file is made in the app data directory. switch to it to open or create it.
+ */
+async addVault(name: string, path: string | null) : Promise<Result<VaultProfile, string>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("add_vault", { name, path }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+/**
+ * lock the open vault and open `name` instead. returns whether it loaded, or still has to be created
+ */
+async switchVault(name: string) : Promise<Result<StartupState, string>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("switch_vault", { name }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+/**
+ * take a vault off the list. with `delete_file`, the vault file and its backups are deleted too
+ */
+async removeVault(name: string, deleteFile: boolean) : Promise<Result<null, string>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("remove_vault", { name, deleteFile }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async changeVaultPassword(oldPassword: string, newPassword: string) : Promise<Result<null, string>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("change_vault_password", { oldPassword, newPassword }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async vaultKdfParams() : Promise<Result<KdfParams, string>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("vault_kdf_params") };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+/**
+ * re-derive the vault key with stronger argon2 parameters
+ */
+async upgradeVaultKdf(password: string, params: KdfParams) : Promise<Result<null, string>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("upgrade_vault_kdf", { password, params }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async vaultLockSettings() : Promise<Result<LockSettings, string>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("vault_lock_settings") };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+/**
+ * change when the vault locks itself. takes effect for the current session straight away
+ */
+async setVaultLockSettings(settings: LockSettings) : Promise<Result<null, string>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("set_vault_lock_settings", { settings }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async listVaultBackups() : Promise<VaultBackup[]> {
+    return await TAURI_INVOKE("list_vault_backups");
+},
+/**
+ * replace the vault with one of its backups. the backup's password and HMAC are checked first,
+ * and the restored vault is left unlocked.
+ */
+async restoreVaultBackup(generation: number, password: string) : Promise<Result<null, string>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("restore_vault_backup", { generation, password }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
 async vaultExists() : Promise<boolean> {
     return await TAURI_INVOKE("vault_exists");
 },
-async generateKeypair(name: string, format: KeyFormat | null) : Promise<Result<null, string>> {
+async generateKeypair(name: string, format: KeyFormat | null, expiresAt: number | null) : Promise<Result<null, string>> {
     try {
-    return { status: "ok", data: await TAURI_INVOKE("generate_keypair", { name, format }) };
+    return { status: "ok", data: await TAURI_INVOKE("generate_keypair", { name, format, expiresAt }) };
 } catch (e) {
     if(e instanceof Error) throw e;
     else return { status: "error", error: e  as any };
 }
 },
-async encryptFile(recipient: EncryptionMethod, reader: TAURI_CHANNEL<FileOperationProgress>, files: string[], armor: boolean | null) : Promise<Result<null, string>> {
+async encryptFile(recipient: EncryptionMethod, reader: TAURI_CHANNEL<FileOperationProgress>, files: string[], armor: boolean | null, output: OutputOptions | null) : Promise<Result<null, string>> {
     try {
-    return { status: "ok", data: await TAURI_INVOKE("encrypt_file", { recipient, reader, files, armor }) };
+    return { status: "ok", data: await TAURI_INVOKE("encrypt_file", { recipient, reader, files, armor, output }) };
 } catch (e) {
     if(e instanceof Error) throw e;
     else return { status: "error", error: e  as any };
 }
 },
-async decryptFile(privateKey: string, reader: TAURI_CHANNEL<FileOperationProgress>, files: string[], method: DecryptionMethod) : Promise<Result<null, string>> {
+async decryptFile(privateKey: string, reader: TAURI_CHANNEL<FileOperationProgress>, files: string[], method: DecryptionMethod, output: OutputOptions | null) : Promise<Result<null, string>> {
     try {
-    return { status: "ok", data: await TAURI_INVOKE("decrypt_file", { privateKey, reader, files, method }) };
+    return { status: "ok", data: await TAURI_INVOKE("decrypt_file", { privateKey, reader, files, method, output }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+/**
+ * pack whole folders into one tar archive, optionally compressed with zstd, and encrypt it into a
+ * single file. it's all one stream, so nothing unencrypted is written anywhere
+ */
+async encryptDirectories(recipient: EncryptionMethod, reader: TAURI_CHANNEL<FileOperationProgress>, directories: string[], armor: boolean | null, compress: boolean | null, output: OutputOptions | null) : Promise<Result<null, string>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("encrypt_directories", { recipient, reader, directories, armor, compress, output }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+/**
+ * decrypt an archive made by `encrypt_directories` and extract it into `destination`. entries that
+ * would land outside it, or links pointing out of it, stop the extraction, and anything already
+ * extracted is removed again
+ */
+async decryptArchive(privateKey: string, reader: TAURI_CHANNEL<FileOperationProgress>, file: string, method: DecryptionMethod, destination: string) : Promise<Result<null, string>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("decrypt_archive", { privateKey, reader, file, method, destination }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+/**
+ * the outputs of an `encrypt_file`, `decrypt_file` or `encrypt_directories` call that already
+ * exist. the frontend asks before sending `ConflictPolicy::Overwrite` for these
+ */
+async checkOutputConflicts(files: string[], output: OutputOptions | null, kind: OutputKind) : Promise<Result<string[], string>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("check_output_conflicts", { files, output, kind }) };
 } catch (e) {
     if(e instanceof Error) throw e;
     else return { status: "error", error: e  as any };
@@ -65,9 +226,75 @@ async exportKey(key: string, path: string, mode: KeyExportMode) : Promise<Result
     else return { status: "error", error: e  as any };
 }
 },
-async importKey(name: string, path: string) : Promise<Result<string, string>> {
+/**
+ * decrypt a private key to show it. the vault password has to have been entered again (with
+ * `authenticate`) in the last minute, and the reveal is recorded on the key
+ */
+async revealPrivateKey(id: string) : Promise<Result<string, string>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("reveal_private_key", { id }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async importKey(name: string, path: string, expiresAt: number | null) : Promise<Result<ImportReport, string>> {
     try {
-    return { status: "ok", data: await TAURI_INVOKE("import_key", { name, path }) };
+    return { status: "ok", data: await TAURI_INVOKE("import_key", { name, path, expiresAt }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+/**
+ * import every public key in an age recipients file. secret keys in it are reported as invalid
+ */
+async importRecipients(name: string, path: string, expiresAt: number | null) : Promise<Result<ImportReport, string>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("import_recipients", { name, path, expiresAt }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+/**
+ * write the public keys of `ids` (or of every key that can be encrypted to) as an age recipients
+ * file, with each key's name in a comment
+ */
+async exportRecipients(ids: string[] | null, path: string) : Promise<Result<null, string>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("export_recipients", { ids, path }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+/**
+ * rename a key and replace its email, notes and tags
+ */
+async updateKeyMetadata(id: string, update: KeyMetadataUpdate) : Promise<Result<KeyMetadata, string>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("update_key_metadata", { id, update }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
+async searchKeys(search: KeySearch) : Promise<KeyMetadata[]> {
+    return await TAURI_INVOKE("search_keys", { search });
+},
+/**
+ * keys that expire within `days` days, or already have, soonest first
+ */
+async expiringKeys(days: number) : Promise<KeyMetadata[]> {
+    return await TAURI_INVOKE("expiring_keys", { days });
+},
+/**
+ * replace one of your keys with a newly generated one. the old key is kept for decryption only
+ */
+async rotateKey(id: string, expiresAt: number | null) : Promise<Result<KeyMetadata, string>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("rotate_key", { id, expiresAt }) };
 } catch (e) {
     if(e instanceof Error) throw e;
     else return { status: "error", error: e  as any };
@@ -84,6 +311,17 @@ async deleteKey(id: string) : Promise<Result<null, string>> {
 async fetchKey(name: string) : Promise<KeyMetadata | null> {
     return await TAURI_INVOKE("fetch_key", { name });
 },
+/**
+ * check a fingerprint someone read out against the stored key `id`
+ */
+async verifyFingerprint(id: string, fingerprint: string) : Promise<Result<boolean, string>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("verify_fingerprint", { id, fingerprint }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
 async authenticate() : Promise<Result<VaultStatusUpdate, string>> {
     try {
     return { status: "ok", data: await TAURI_INVOKE("authenticate") };
@@ -95,9 +333,9 @@ async authenticate() : Promise<Result<VaultStatusUpdate, string>> {
 async vaultUnlocked() : Promise<boolean> {
     return await TAURI_INVOKE("vault_unlocked");
 },
-async importKeyText(name: string, keyContent: string) : Promise<Result<string, string>> {
+async importKeyText(name: string, keyContent: string, expiresAt: number | null) : Promise<Result<ImportReport, string>> {
     try {
-    return { status: "ok", data: await TAURI_INVOKE("import_key_text", { name, keyContent }) };
+    return { status: "ok", data: await TAURI_INVOKE("import_key_text", { name, keyContent, expiresAt }) };
 } catch (e) {
     if(e instanceof Error) throw e;
     else return { status: "error", error: e  as any };
@@ -123,7 +361,7 @@ async lockVault() : Promise<Result<null, string>> {
 }
 },
 /**
- * command to regenerate the public keys of all identities.
+ * command to regenerate the public keys of all identities. public-only keys are kept.
  */
 async regeneratePublicIdentities() : Promise<Result<null, string>> {
     try {
@@ -133,6 +371,23 @@ async regeneratePublicIdentities() : Promise<Result<null, string>> {
     else return { status: "error", error: e  as any };
 }
 },
+/**
+ * what changed in the vault, if it failed its integrity check on unlock
+ */
+async integrityReport() : Promise<IntegrityReport | null> {
+    return await TAURI_INVOKE("integrity_report");
+},
+/**
+ * make a vault that failed its integrity check writable again
+ */
+async repairVault(action: RepairAction) : Promise<Result<null, string>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("repair_vault", { action }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
+},
 async validateKeyFile(path: string) : Promise<Result<null, string>> {
     try {
     return { status: "ok", data: await TAURI_INVOKE("validate_key_file", { path }) };
@@ -175,6 +430,17 @@ async downgradeHybridPublicKey(publicKey: string) : Promise<Result<string, strin
     if(e instanceof Error) throw e;
     else return { status: "error", error: e  as any };
 }
+},
+/**
+ * reply to a `plugin-prompt` event
+ */
+async answerPluginPrompt(id: number, answer: PluginAnswer) : Promise<Result<null, string>> {
+    try {
+    return { status: "ok", data: await TAURI_INVOKE("answer_plugin_prompt", { id, answer }) };
+} catch (e) {
+    if(e instanceof Error) throw e;
+    else return { status: "error", error: e  as any };
+}
 }
 }
 
@@ -182,9 +448,11 @@ async downgradeHybridPublicKey(publicKey: string) : Promise<Result<string, strin
 
 
 export const events = __makeEvents__<{
-vaultStatusUpdate: VaultStatusUpdate
+vaultStatusUpdate: VaultStatusUpdate,
+pluginPrompt: PluginPrompt
 }>({
-vaultStatusUpdate: "vault-status-update"
+vaultStatusUpdate: "vault-status-update",
+pluginPrompt: "plugin-prompt"
 })
 
 /** user-defined constants **/
@@ -193,19 +461,50 @@ vaultStatusUpdate: "vault-status-update"
 
 /** user-defined types **/
 
+/**
+ * what to do when an output file already exists
+ */
+export type ConflictPolicy = "Fail" | "Rename" | "Overwrite"
 export type DecryptionMethod = "X25519" | "Scrypt"
 /**
  * a type storing an XChaCha20Poly1305 `ciphertext` and `nonce`. both are of type `Vec<u8>`.
+ * 
+ * `bound` is `true` if the ciphertext was sealed with its key entry as associated data (see `secret_aad`).
+ * private keys from before format version 3 aren't, and get re-sealed the next time the vault is saved.
  */
-export type EncryptedSecret = { nonce: number[]; ciphertext: number[] }
+export type EncryptedSecret = { nonce: number[]; ciphertext: number[]; bound: boolean }
 export type EncryptionMethod = string[] | string
 export type FileOperationProgress = { read_bytes: number; total_bytes: number; current_file: string }
+export type ImportLine = { line: number; outcome: ImportOutcome }
+/**
+ * what happened to one line of the imported text
+ */
+export type ImportOutcome = { status: "imported"; detail: string } | { status: "skipped"; detail: string } | { status: "invalid"; detail: string }
+/**
+ * every non-blank line of the imported text, and what happened to it
+ */
+export type ImportReport = { imported: number; lines: ImportLine[] }
+/**
+ * what changed in a vault since it was last saved. built when the vault fails its integrity check.
+ * entries are redacted.
+ */
+export type IntegrityReport = { added: KeyMetadata[]; modified: KeyMetadata[]; removed: string[]; header_modified: boolean; unverifiable: boolean }
+/**
+ * the argon2 parameters a vault key is derived with. these are stored in the vault file so that
+ * a change to the defaults of the `argon2` crate can never lock anyone out of an existing vault.
+ * `memory_cost` is in KiB.
+ */
+export type KdfParams = { algorithm: string; version: number; memory_cost: number; time_cost: number; parallelism: number }
 export type KeyExportMode = "PostQuantum" | "X25519"
 export type KeyFormat = "X25519" | "PostQuantum"
 /**
  * representation of a key object. id is a cuid2
  */
-export type KeyMetadata = { id: string; name: string; key_type: KeyType; date_created: SystemTime; contents: KeyPair }
+export type KeyMetadata = { id: string; name: string; key_type: KeyType; date_created: SystemTime; contents: KeyPair; email: string | null; notes: string | null; tags: string[]; date_modified: SystemTime; expires_at: SystemTime | null; retired_at: SystemTime | null; successor: string | null; predecessor: string | null; reveals: KeyReveal[]; fingerprint?: string | null }
+/**
+ * the new name, email, notes and tags for a key. every field replaces the old value
+ */
+export type KeyMetadataUpdate = { name: string; email: string | null; notes: string | null; tags: string[] }
 /**
  * an object storing the actual key contents.
  * 
@@ -214,9 +513,45 @@ export type KeyMetadata = { id: string; name: string; key_type: KeyType; date_cr
  * `private` is `Option<EncryptedSecret>`, being an object containing a `nonce` and `ciphertext` (both `Vec<u8>`)
  */
 export type KeyPair = { public: string; private: EncryptedSecret | null }
+export type KeyReveal = { at: SystemTime; kind: RevealKind }
+/**
+ * keys match if they have every one of `tags`, and `text` (if set) appears in their name, email,
+ * notes or tags. both are case-insensitive.
+ */
+export type KeySearch = { text: string | null; tags: string[] }
 export type KeyType = "Public" | "Private"
+/**
+ * when an unlocked vault locks itself again. `None` turns a limit off.
+ */
+export type LockSettings = { idle_timeout_secs: number | null; max_session_secs: number | null }
+export type OutputKind = "Encrypted" | "Decrypted" | "Archive" | "CompressedArchive"
+export type OutputOptions = { directory: string | null; name_template: string | null; on_conflict: ConflictPolicy | null }
+export type PluginAnswer = { kind: "confirm"; value: boolean } | { kind: "text"; value: string } | { kind: "cancel" }
+/**
+ * `source` is who's asking, e.g. `age-plugin-yubikey`, or `ssh key` for an ssh key's passphrase
+ */
+export type PluginPrompt = { kind: "message"; source: string; message: string } | { kind: "confirm"; id: number; source: string; message: string; yes: string; no: string | null } | { kind: "text"; id: number; source: string; description: string; secret: boolean }
+/**
+ * how to get a vault that failed its integrity check writable again.
+ * either way, public-only keys that weren't flagged are kept, and the public half of every
+ * private key is re-derived from the private key.
+ */
+export type RepairAction = "KeepAll" | "RemoveFlagged"
+export type RevealKind = "Shown" | "Exported"
+/**
+ * what happened to the vault when the app started. if loading failed, `vault` is `None` until the
+ * user restores a backup, opens another file or starts over.
+ */
+export type StartupState = { state: "firstOpen" } | { state: "loaded" } | { state: "loadFailed"; error: VaultLoadError }
 export type SystemTime = { duration_since_epoch: number; duration_since_unix_epoch: number }
-export type VaultStatusUpdate = "unlocked" | "verificationFail" | "authenticationCancel" | "locked"
+export type VaultBackup = { generation: number; date_modified: SystemTime; size: number }
+/**
+ * why a vault file couldn't be loaded
+ */
+export type VaultLoadError = { kind: "missing" } | { kind: "unreadable"; message: string } | { kind: "corrupt"; message: string } | { kind: "unsupportedVersion"; message: number }
+export type VaultProfile = { name: string; path: string }
+export type VaultProfiles = { active: string; vaults: VaultProfile[] }
+export type VaultStatusUpdate = "unlocked" | "verificationFail" | "authenticationCancel" | "locked" | { lockedOut: { seconds: number } }
 
 /** tauri-specta globals **/
 